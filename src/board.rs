@@ -0,0 +1,704 @@
+use crate::util::{opposite, POSSIBLE_MOVES};
+use serde::{Deserialize, Serialize};
+use sokoban::Direction::{Down, Left, Right, Up};
+use sokoban::{Direction, State as SokobanState, Tile};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+
+fn bitset(squares: usize) -> Vec<u64> {
+    vec![0; squares.div_ceil(64)]
+}
+
+fn get_bit(bits: &[u64], index: usize) -> bool {
+    bits[index / 64] & (1 << (index % 64)) != 0
+}
+
+fn set_bit(bits: &mut [u64], index: usize, value: bool) {
+    if value {
+        bits[index / 64] |= 1 << (index % 64);
+    } else {
+        bits[index / 64] &= !(1 << (index % 64));
+    }
+}
+
+fn ones(bits: &[u64]) -> impl Iterator<Item = usize> + '_ {
+    bits.iter().enumerate().flat_map(|(word, &bits)| {
+        (0..64)
+            .filter(move |bit| bits & (1 << bit) != 0)
+            .map(move |bit| word * 64 + bit)
+    })
+}
+
+// the parts of a puzzle that never change, shared by every compact state of that puzzle
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticBoard {
+    walls: Vec<u64>,
+    targets: Vec<(usize, usize)>,
+    rows: usize,
+    cols: usize,
+}
+
+impl StaticBoard {
+    pub fn new(puzzle: &SokobanState) -> Self {
+        let mut walls = bitset(puzzle.rows() * puzzle.cols());
+        for item in puzzle.iter().filter(|item| item.tile() == Tile::Wall) {
+            let (r, c) = item.position();
+            set_bit(&mut walls, r * puzzle.cols() + c, true);
+        }
+        Self {
+            walls,
+            targets: puzzle.targets().to_vec(),
+            rows: puzzle.rows(),
+            cols: puzzle.cols(),
+        }
+    }
+
+    fn position(&self, index: usize) -> (usize, usize) {
+        (index / self.cols, index % self.cols)
+    }
+
+    fn index(&self, (r, c): (usize, usize)) -> Option<usize> {
+        (r < self.rows && c < self.cols).then_some(r * self.cols + c)
+    }
+
+    fn step(&self, index: usize, direction: Direction) -> Option<usize> {
+        self.index(direction.go(self.position(index))?)
+    }
+
+    pub fn targets(&self) -> &[(usize, usize)] {
+        &self.targets
+    }
+
+    // like is_dead_square on the full state
+    pub fn is_dead_square(&self, position: (usize, usize)) -> bool {
+        if self.targets.contains(&position) {
+            return false;
+        }
+        let Some(index) = self.index(position) else {
+            return false;
+        };
+        let is_wall = |direction| {
+            self.step(index, direction)
+                .is_some_and(|next| get_bit(&self.walls, next))
+        };
+        (is_wall(Up) || is_wall(Down)) && (is_wall(Left) || is_wall(Right))
+    }
+
+    // the dynamic part of a state of this puzzle
+    pub fn compact(&self, puzzle: &SokobanState) -> CompactState {
+        let mut crates = bitset(self.rows * self.cols);
+        for item in puzzle.iter().filter(|item| item.tile() == Tile::Crate) {
+            let (r, c) = item.position();
+            set_bit(&mut crates, r * self.cols + c, true);
+        }
+        let (r, c) = puzzle.player();
+        CompactState {
+            crates,
+            player: r * self.cols + c,
+        }
+    }
+
+    // the full state again; the move counter starts over from zero
+    pub fn expand(&self, compact: &CompactState) -> SokobanState {
+        let tiles = (0..self.rows * self.cols)
+            .map(|index| {
+                if get_bit(&self.walls, index) {
+                    Tile::Wall
+                } else if get_bit(&compact.crates, index) {
+                    Tile::Crate
+                } else {
+                    Tile::Floor
+                }
+            })
+            .collect();
+        SokobanState::new(
+            tiles,
+            self.position(compact.player),
+            self.targets.clone(),
+            self.rows,
+            self.cols,
+        )
+        .expect("compact state doesn't fit its board")
+    }
+
+    // replays the moves, or gives the index of the first illegal move and the state before it
+    pub fn replay(
+        &self,
+        initial: &CompactState,
+        moves: &[Direction],
+    ) -> Result<CompactState, (usize, CompactState)> {
+        let mut current = initial.clone();
+        for (i, &direction) in moves.iter().enumerate() {
+            if current.move_player(self, direction).is_none() {
+                return Err((i, current));
+            }
+        }
+        Ok(current)
+    }
+
+    // the state before the first push and after every push, along with the state the moves end in
+    pub fn trajectory(
+        &self,
+        initial: &CompactState,
+        moves: &[Direction],
+    ) -> Option<(Vec<CompactState>, CompactState)> {
+        let mut current = initial.clone();
+        let mut trajectory = vec![current.clone()];
+        for &direction in moves {
+            if current.move_player(self, direction)? {
+                trajectory.push(current.clone());
+            }
+        }
+        Some((trajectory, current))
+    }
+
+    pub fn count_pushes(&self, initial: &CompactState, moves: &[Direction]) -> Option<usize> {
+        let mut current = initial.clone();
+        moves.iter().try_fold(0, |pushes, &direction| {
+            Some(pushes + usize::from(current.move_player(self, direction)?))
+        })
+    }
+
+    // the lengths of every prefix of moves that ends in a push, starting with the empty prefix
+    pub fn push_boundaries(
+        &self,
+        initial: &CompactState,
+        moves: &[Direction],
+    ) -> Option<Vec<usize>> {
+        let mut boundaries = vec![0];
+        let mut current = initial.clone();
+        for (i, &direction) in moves.iter().enumerate() {
+            if current.move_player(self, direction)? {
+                boundaries.push(i + 1);
+            }
+        }
+        Some(boundaries)
+    }
+
+    // replays the moves and lists each push as (crate, direction), where crates are numbered by
+    // their order in the initial state; also returns where each crate ends up
+    #[allow(clippy::type_complexity)]
+    pub fn crate_pushes(
+        &self,
+        initial: &CompactState,
+        moves: &[Direction],
+    ) -> Option<(Vec<(usize, Direction)>, Vec<(usize, usize)>)> {
+        let mut positions = ones(&initial.crates).collect::<Vec<_>>();
+        let mut pushes = Vec::new();
+        let mut current = initial.clone();
+        for &direction in moves {
+            let next = self.step(current.player, direction);
+            if current.move_player(self, direction)? {
+                let pushed = positions
+                    .iter()
+                    .position(|&position| Some(position) == next)
+                    .unwrap();
+                positions[pushed] = self.step(current.player, direction).unwrap();
+                pushes.push((pushed, direction));
+            }
+        }
+        let positions = positions
+            .into_iter()
+            .map(|index| self.position(index))
+            .collect();
+        Some((pushes, positions))
+    }
+
+    // the moves which lead back to from along the directions each square was entered by
+    fn path(&self, entered: &[Option<Direction>], from: usize, to: usize) -> Vec<Direction> {
+        let mut moves = Vec::new();
+        let mut index = to;
+        while index != from {
+            let direction = entered[index].unwrap();
+            moves.push(direction);
+            index = self.step(index, opposite(direction)).unwrap();
+        }
+        moves.reverse();
+        moves
+    }
+}
+
+// the parts of a puzzle that move: a bit per square holding a crate, and where the player is
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CompactState {
+    crates: Vec<u64>,
+    player: usize,
+}
+
+impl CompactState {
+    pub fn player(&self, board: &StaticBoard) -> (usize, usize) {
+        board.position(self.player)
+    }
+
+    // the crate bitset, to compare configurations regardless of the player
+    pub fn crates(&self) -> &[u64] {
+        &self.crates
+    }
+
+    // like find_crates on the expanded state
+    pub fn crate_positions(&self, board: &StaticBoard) -> Vec<(usize, usize)> {
+        ones(&self.crates)
+            .map(|index| board.position(index))
+            .collect()
+    }
+
+    // whether the player could stand there
+    pub fn is_floor(&self, board: &StaticBoard, position: (usize, usize)) -> bool {
+        board
+            .index(position)
+            .is_some_and(|index| !self.blocked(board, index))
+    }
+
+    fn blocked(&self, board: &StaticBoard, index: usize) -> bool {
+        get_bit(&board.walls, index) || get_bit(&self.crates, index)
+    }
+
+    // a breadth-first walk from one square to another around the walls and crates, giving the
+    // direction each square on the way was first entered by
+    fn walk(&self, board: &StaticBoard, from: usize, to: usize) -> Option<Vec<Option<Direction>>> {
+        if self.blocked(board, from) || self.blocked(board, to) {
+            return None;
+        }
+        let mut entered = vec![None; board.rows * board.cols];
+        let mut seen = bitset(board.rows * board.cols);
+        set_bit(&mut seen, from, true);
+        let mut queue = VecDeque::from([from]);
+        while let Some(index) = queue.pop_front() {
+            if index == to {
+                return Some(entered);
+            }
+            for direction in POSSIBLE_MOVES {
+                let Some(next) = board.step(index, direction) else {
+                    continue;
+                };
+                if get_bit(&seen, next) || self.blocked(board, next) {
+                    continue;
+                }
+                set_bit(&mut seen, next, true);
+                entered[next] = Some(direction);
+                queue.push_back(next);
+            }
+        }
+        None
+    }
+
+    // like go_to on the expanded state, from where the player stands
+    pub fn go_to(
+        &self,
+        board: &StaticBoard,
+        destination: (usize, usize),
+    ) -> Option<Vec<Direction>> {
+        let destination = board.index(destination)?;
+        let entered = self.walk(board, self.player, destination)?;
+        Some(board.path(&entered, self.player, destination))
+    }
+
+    // like push_to on the expanded state: the crate moves square by square, so long as the player
+    // can get behind it for every push
+    pub fn push_to(
+        &self,
+        board: &StaticBoard,
+        start: (usize, usize),
+        destination: (usize, usize),
+    ) -> Option<Vec<Direction>> {
+        let start = board.index(start)?;
+        let destination = board.index(destination)?;
+        if !get_bit(&self.crates, start) || self.blocked(board, destination) {
+            return None;
+        }
+        if start == destination {
+            return Some(Vec::new());
+        }
+
+        // the crate is only where the search has pushed it to so far
+        let mut hallucinated = self.clone();
+        set_bit(&mut hallucinated.crates, start, false);
+        let mut entered = vec![None; board.rows * board.cols];
+        let mut seen = bitset(board.rows * board.cols);
+        set_bit(&mut seen, start, true);
+        let mut queue = VecDeque::from([start]);
+        let mut found = false;
+        'search: while let Some(position) = queue.pop_front() {
+            // the player stands where the crate was pushed from
+            let player = entered[position].map_or(self.player, |direction: Direction| {
+                board.step(position, opposite(direction)).unwrap()
+            });
+            set_bit(&mut hallucinated.crates, position, true);
+            for direction in POSSIBLE_MOVES {
+                let (Some(next), Some(push_point)) = (
+                    board.step(position, direction),
+                    board.step(position, opposite(direction)),
+                ) else {
+                    continue;
+                };
+                if get_bit(&seen, next)
+                    || hallucinated.blocked(board, next)
+                    || hallucinated.walk(board, player, push_point).is_none()
+                {
+                    continue;
+                }
+                set_bit(&mut seen, next, true);
+                entered[next] = Some(direction);
+                if next == destination {
+                    found = true;
+                    break 'search;
+                }
+                queue.push_back(next);
+            }
+            set_bit(&mut hallucinated.crates, position, false);
+        }
+        if !found {
+            return None;
+        }
+
+        let mut current = self.clone();
+        let mut moves = Vec::new();
+        let mut position = start;
+        for direction in board.path(&entered, start, destination) {
+            let push_point = board.step(position, opposite(direction)).unwrap();
+            let walk = current
+                .walk(board, current.player, push_point)
+                .expect("the search made sure the player can push here");
+            for step in board
+                .path(&walk, current.player, push_point)
+                .into_iter()
+                .chain([direction])
+            {
+                current.move_player(board, step).unwrap();
+                moves.push(step);
+            }
+            position = board.step(position, direction).unwrap();
+        }
+        Some(moves)
+    }
+
+    // same rules as move_player, but in place; tells whether a crate was pushed, or leaves the
+    // state untouched and returns None if the move is illegal
+    pub fn move_player(&mut self, board: &StaticBoard, direction: Direction) -> Option<bool> {
+        let next = board.step(self.player, direction)?;
+        if get_bit(&board.walls, next) {
+            return None;
+        }
+        let pushes = get_bit(&self.crates, next);
+        if pushes {
+            let beyond = board.step(next, direction)?;
+            if get_bit(&board.walls, beyond) || get_bit(&self.crates, beyond) {
+                return None;
+            }
+            set_bit(&mut self.crates, next, false);
+            set_bit(&mut self.crates, beyond, true);
+        }
+        self.player = next;
+        Some(pushes)
+    }
+
+    // how many targets hold a crate, like count_filled on the expanded state
+    pub fn filled(&self, board: &StaticBoard) -> usize {
+        board
+            .targets
+            .iter()
+            .filter(|&&(r, c)| get_bit(&self.crates, r * board.cols + c))
+            .count()
+    }
+
+    pub fn in_solution_state(&self, board: &StaticBoard) -> bool {
+        board
+            .targets
+            .iter()
+            .all(|&(r, c)| get_bit(&self.crates, r * board.cols + c))
+    }
+
+    // agrees with hash_sokoban_state on the expanded state
+    pub fn state_hash(&self, board: &StaticBoard, include_player: bool) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for index in ones(&self.crates) {
+            board.position(index).hash(&mut hasher);
+        }
+        if include_player {
+            board.position(self.player).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    // like state_hash with the player, but the same wherever the player stands in its region:
+    // the region is named by the first square of it in reading order
+    pub fn region_hash(&self, board: &StaticBoard) -> u64 {
+        let mut region = self.player;
+        let mut seen = bitset(board.rows * board.cols);
+        set_bit(&mut seen, self.player, true);
+        let mut queue = VecDeque::from([self.player]);
+        while let Some(index) = queue.pop_front() {
+            region = region.min(index);
+            for direction in POSSIBLE_MOVES {
+                let Some(next) = board.step(index, direction) else {
+                    continue;
+                };
+                if get_bit(&seen, next) || self.blocked(board, next) {
+                    continue;
+                }
+                set_bit(&mut seen, next, true);
+                queue.push_back(next);
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        for index in ones(&self.crates) {
+            board.position(index).hash(&mut hasher);
+        }
+        board.position(region).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::board::StaticBoard;
+    use crate::util::{go_to, hash_sokoban_state, is_push};
+    use sokoban::Direction::{Down, Left, Right, Up};
+    use sokoban::{State as SokobanState, Tile};
+
+    #[test]
+    fn test_go_to() {
+        let puzzle = SokobanState::parse(
+            &br#"
+##########
+#____#___#
+#_x__#_m_#
+#________#
+#____#___#
+##########
+"#[..],
+        )
+        .unwrap();
+        let board = StaticBoard::new(&puzzle);
+        let compact = board.compact(&puzzle);
+
+        let moves = compact
+            .go_to(&board, (1, 8))
+            .expect("Couldn't find path to (1, 8)!");
+        assert_eq!(
+            moves.len(),
+            go_to(puzzle.player(), (1, 8), &puzzle).unwrap().len()
+        );
+        let mut reached = compact.clone();
+        for direction in moves {
+            assert_eq!(reached.move_player(&board, direction), Some(false));
+        }
+        assert_eq!(reached.player(&board), (1, 8));
+
+        // walls and crates are in the way
+        assert!(compact.go_to(&board, (0, 0)).is_none());
+        assert!(compact.go_to(&board, (2, 7)).is_none());
+    }
+
+    #[test]
+    fn test_push_to_simple() {
+        let puzzle = SokobanState::parse(
+            &br#"
+####################
+#__________________#
+#__________________#
+#______________m___#
+#_____________x____#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+#__________________#
+####################
+"#[..],
+        )
+        .unwrap();
+        let board = StaticBoard::new(&puzzle);
+        let compact = board.compact(&puzzle);
+
+        let moves = compact
+            .push_to(&board, (3, 15), (15, 3))
+            .expect("Couldn't find path to (15, 3)!");
+        let puzzle = board.expand(&board.replay(&compact, &moves).unwrap());
+
+        assert_eq!(puzzle[(15, 3)], Tile::Crate);
+    }
+
+    #[test]
+    fn test_push_to_around_wall() {
+        let puzzle = SokobanState::parse(
+            &br#"
+####################
+#________#_________#
+#________#_____m___#
+#________#____x____#
+#________#_________#
+#________#_________#
+#________#_________#
+#________#_________#
+#________#_________#
+#________#_________#
+#________#_________#
+#________#_________#
+#________#_________#
+#________#_________#
+#________#_________#
+#________#_________#
+#__________________#
+#__________________#
+####################
+"#[..],
+        )
+        .unwrap();
+        let board = StaticBoard::new(&puzzle);
+        let compact = board.compact(&puzzle);
+
+        let moves = compact
+            .push_to(&board, (2, 15), (3, 3))
+            .expect("Couldn't find path to (3, 3)!");
+        let puzzle = board.expand(&board.replay(&compact, &moves).unwrap());
+
+        assert_eq!(puzzle[(3, 3)], Tile::Crate);
+
+        // nor can it be pushed into a wall
+        let compact = board.compact(&puzzle);
+        assert!(compact.push_to(&board, (3, 3), (3, 9)).is_none());
+    }
+
+    #[test]
+    fn test_push_boundaries() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#######
+#_____#
+#_xm._#
+#_____#
+#######
+"#[..],
+        )
+        .unwrap();
+        let board = StaticBoard::new(&puzzle);
+        let compact = board.compact(&puzzle);
+
+        let moves = [Right, Right, Up, Right, Down, Left, Down];
+        let boundaries = board
+            .push_boundaries(&compact, &moves)
+            .expect("Should not make invalid moves!");
+
+        assert_eq!(boundaries, vec![0, 1, 2, 5]);
+        assert!(board.push_boundaries(&compact, &[Up, Up]).is_none());
+    }
+
+    #[test]
+    fn test_crate_pushes() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#######
+#_____#
+#_xm._#
+#__m__#
+#_____#
+#######
+"#[..],
+        )
+        .unwrap();
+        let board = StaticBoard::new(&puzzle);
+        let compact = board.compact(&puzzle);
+
+        let moves = [Right, Left, Down, Right, Right];
+        let (pushes, positions) = board
+            .crate_pushes(&compact, &moves)
+            .expect("Should not make invalid moves!");
+
+        assert_eq!(pushes, vec![(0, Right), (1, Right), (1, Right)]);
+        assert_eq!(positions, vec![(2, 4), (3, 5)]);
+    }
+
+    #[test]
+    fn test_compact_state() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#######
+#_____#
+#_m_m_#
+#__x__#
+#.___.#
+#######
+"#[..],
+        )
+        .unwrap();
+        let board = StaticBoard::new(&puzzle);
+        let mut compact = board.compact(&puzzle);
+        assert_eq!(board.expand(&compact), puzzle);
+
+        let mut expected = puzzle;
+        for direction in [
+            Up, Up, Left, Down, Down, Right, Down, Left, Up, Up, Up, Right, Right, Down, Down,
+            Left, Down, Right,
+        ] {
+            let pushed = is_push(&expected, direction);
+            expected = expected.move_player(direction).unwrap();
+            assert_eq!(compact.move_player(&board, direction), Some(pushed));
+            assert_eq!(
+                compact.state_hash(&board, true),
+                hash_sokoban_state(&expected, true)
+            );
+        }
+        assert!(compact.in_solution_state(&board));
+        assert_eq!(board.compact(&board.expand(&compact)), compact);
+
+        // an illegal move leaves the state alone
+        let before = compact.clone();
+        assert_eq!(compact.move_player(&board, Down), None);
+        assert_eq!(compact, before);
+    }
+
+    #[test]
+    fn test_region_hash() {
+        let parse = |level: &[u8]| {
+            let puzzle = SokobanState::parse(level).unwrap();
+            let board = StaticBoard::new(&puzzle);
+            let compact = board.compact(&puzzle);
+            (
+                compact.state_hash(&board, true),
+                compact.region_hash(&board),
+            )
+        };
+        let corner = parse(
+            &br#"
+######
+#x_#_#
+#__m.#
+######
+"#[..],
+        );
+        let walked = parse(
+            &br#"
+######
+#__#_#
+#_xm.#
+######
+"#[..],
+        );
+        let sealed_off = parse(
+            &br#"
+######
+#__#x#
+#__m.#
+######
+"#[..],
+        );
+
+        // walking around the region keeps the hash, unlike the exact one
+        assert_ne!(corner.0, walked.0);
+        assert_eq!(corner.1, walked.1);
+        // but the crate seals off the other side
+        assert_ne!(corner.1, sealed_off.1);
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::{Duration, Instant};
+
+use libafl::corpus::Corpus;
+use libafl::state::{HasCorpus, HasExecutions, HasMetadata};
+use libafl_bolts::impl_serdeany;
+use serde::{Deserialize, Serialize};
+
+// the limits a campaign gives up searching at; the stages check them too, since a single fuzz_one
+// can run many executions. When this run started isn't saved, as a resumed run sets it again
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BudgetMetadata {
+    max_executions: Option<usize>,
+    max_time: Option<Duration>,
+    max_corpus: Option<usize>,
+    // in bytes
+    max_memory: Option<u64>,
+    // when this run started, and the time spent before it
+    #[serde(skip)]
+    started: Option<(Instant, Duration)>,
+}
+
+impl_serdeany!(BudgetMetadata);
+
+impl BudgetMetadata {
+    pub fn new(
+        max_executions: Option<usize>,
+        max_time: Option<Duration>,
+        max_corpus: Option<usize>,
+        max_memory: Option<u64>,
+        start: Instant,
+        offset: Duration,
+    ) -> Self {
+        Self {
+            max_executions,
+            max_time,
+            max_corpus,
+            max_memory,
+            started: Some((start, offset)),
+        }
+    }
+
+    // which of the budgets, if any, is used up
+    pub fn exhausted(&self, executions: usize, corpus: usize) -> Option<&'static str> {
+        if self.max_executions.is_some_and(|max| executions >= max) {
+            Some("execution")
+        } else if self.max_time.is_some_and(|max| {
+            self.started
+                .is_some_and(|(start, offset)| offset + start.elapsed() >= max)
+        }) {
+            Some("time")
+        } else if self.max_corpus.is_some_and(|max| corpus >= max) {
+            Some("corpus")
+        } else if self
+            .max_memory
+            .is_some_and(|max| resident_memory().is_some_and(|used| used >= max))
+        {
+            Some("memory")
+        } else {
+            None
+        }
+    }
+}
+
+// which of the budgets the state was given, if any, is used up
+pub fn exhausted_budget<S>(state: &S) -> Option<&'static str>
+where
+    S: HasCorpus + HasExecutions + HasMetadata,
+{
+    state
+        .metadata::<BudgetMetadata>()
+        .ok()?
+        .exhausted(*state.executions(), state.corpus().count())
+}
+
+// the resident set size in bytes, where the platform tells us
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb << 10)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::budget::BudgetMetadata;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_budget_exhausted() {
+        let budget = BudgetMetadata::new(
+            Some(30),
+            None,
+            Some(100),
+            None,
+            Instant::now(),
+            Duration::ZERO,
+        );
+        assert_eq!(budget.exhausted(29, 99), None);
+        assert_eq!(budget.exhausted(30, 0), Some("execution"));
+        assert_eq!(budget.exhausted(363, 0), Some("execution"));
+        assert_eq!(budget.exhausted(0, 100), Some("corpus"));
+
+        // a resumed run that already spent its time gives up straight away
+        let budget = BudgetMetadata::new(
+            None,
+            Some(Duration::from_secs(60)),
+            None,
+            None,
+            Instant::now(),
+            Duration::from_secs(90),
+        );
+        assert_eq!(budget.exhausted(0, 0), Some("time"));
+
+        // without knowing when the run started, time doesn't run out
+        let budget: BudgetMetadata =
+            postcard::from_bytes(&postcard::to_allocvec(&budget).unwrap()).unwrap();
+        assert_eq!(budget.exhausted(0, 0), None);
+    }
+}
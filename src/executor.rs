@@ -1,19 +1,30 @@
-use crate::input::SokobanInput;
-use crate::observer::{SokobanObserversTuple, SokobanStateObserver};
-use crate::state::LastHallucinationMetadata;
+use crate::board::{CompactState, StaticBoard};
+use crate::input::{ReverseSokobanInput, SokobanInput};
+use crate::observer::{SokobanObserversTuple, SokobanStateObserver, SokobanTrajectoryObserver};
+use crate::stage::HallucinationExecutor;
+use crate::state::{CampaignStatsMetadata, InvalidInputsMetadata, InvalidMove};
+use libafl::corpus::Corpus;
+use libafl::events::{Event, EventFirer};
 use libafl::executors::{Executor, ExitKind, HasObservers};
+use libafl::monitors::{AggregatorOps, UserStats, UserStatsValue};
 use libafl::observers::{ObserversTuple, UsesObservers};
 use libafl::state::{HasExecutions, HasMetadata, State, UsesState};
 use libafl::Error;
 use sokoban::State as SokobanState;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct SokobanExecutor<OT, S> {
-    initial: SokobanState,
+    board: Arc<StaticBoard>,
+    initial: CompactState,
     observers: OT,
     state_observer_name: String,
+    trajectory_observer_name: Option<String>,
+    hallucination: Option<(Vec<CompactState>, CompactState)>,
+    mutator: Option<String>,
+    verify: bool,
     phantom: PhantomData<S>,
 }
 
@@ -21,14 +32,75 @@ impl<OT, S> SokobanExecutor<OT, S>
 where
     OT: SokobanObserversTuple,
 {
-    pub fn new(initial: SokobanState, observers: OT) -> Self {
+    pub fn new(board: Arc<StaticBoard>, initial: CompactState, observers: OT) -> Self {
         Self {
+            board,
             initial,
             state_observer_name: observers.sokoban_observer_name().to_string(),
+            trajectory_observer_name: None,
+            hallucination: None,
+            mutator: None,
+            verify: cfg!(debug_assertions),
             observers,
             phantom: PhantomData,
         }
     }
+
+    // fills in the trajectory observer, from the hallucination where there is one
+    pub fn with_trajectory(mut self, name: &str) -> Self {
+        self.trajectory_observer_name = Some(name.to_string());
+        self
+    }
+
+    // checks every hallucination against a replay, even in release builds
+    pub fn with_verification(mut self) -> Self {
+        self.verify = true;
+        self
+    }
+
+    // keeps the input for the report at the end, blaming the mutator which made it
+    fn record_invalid<EM>(
+        &self,
+        state: &mut S,
+        mgr: &mut EM,
+        input: &SokobanInput,
+        why: InvalidMove,
+        mutator: Option<String>,
+    ) -> Result<(), Error>
+    where
+        EM: EventFirer<State = S>,
+        S: State<Input = SokobanInput> + HasMetadata,
+    {
+        if let Ok(invalid) = state.metadata_mut::<InvalidInputsMetadata>() {
+            invalid.add(input.clone(), why, mutator)?;
+            let count = invalid.corpus().count();
+            mgr.fire(
+                state,
+                Event::UpdateUserStats {
+                    name: "invalid_inputs".to_string(),
+                    value: UserStats::new(UserStatsValue::Number(count as u64), AggregatorOps::Sum),
+                    phantom: Default::default(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<OT, S> HallucinationExecutor for SokobanExecutor<OT, S> {
+    fn hallucinate(
+        &mut self,
+        mutator: &str,
+        hallucination: Option<(Vec<CompactState>, CompactState)>,
+    ) {
+        self.hallucination = hallucination;
+        self.mutator = Some(mutator.to_string());
+    }
+
+    fn take_hallucination(&mut self) -> Option<(Vec<CompactState>, CompactState)> {
+        self.mutator = None;
+        self.hallucination.take()
+    }
 }
 
 impl<OT, S> UsesState for SokobanExecutor<OT, S>
@@ -40,7 +112,7 @@ where
 
 impl<EM, OT, S, Z> Executor<EM, Z> for SokobanExecutor<OT, S>
 where
-    EM: UsesState<State = Self::State>,
+    EM: EventFirer<State = Self::State>,
     OT: ObserversTuple<S> + SokobanObserversTuple + Debug,
     S: State<Input = SokobanInput> + HasMetadata + HasExecutions + Debug,
     Z: UsesState<State = Self::State>,
@@ -49,42 +121,148 @@ where
         &mut self,
         _fuzzer: &mut Z,
         state: &mut Self::State,
-        _mgr: &mut EM,
+        mgr: &mut EM,
         input: &Self::Input,
     ) -> Result<ExitKind, Error> {
-        let hallucinated = state
-            .metadata_mut::<LastHallucinationMetadata>()
-            .ok()
-            .and_then(|metadata| metadata.hallucination_mut().take());
+        let mut hallucinated = self.hallucination.take();
+        let mutator = self.mutator.take();
 
         *state.executions_mut() += 1;
 
-        #[cfg(debug_assertions)]
-        if let Some(hallucinated) = hallucinated.as_ref() {
-            debug_assert_eq!(
-                hallucinated,
-                &input
-                    .moves()
-                    .iter()
-                    .cloned()
-                    .try_fold(self.initial.clone(), |state, dir| state.move_player(dir))
-                    .unwrap()
-            );
+        if self.verify {
+            if let Some((hallucinated_steps, hallucinated_state)) = hallucinated.take() {
+                // an illegal move is left for the replay below to find
+                if let Some((steps, reached)) = self.board.trajectory(&self.initial, input.moves())
+                {
+                    if reached != hallucinated_state || steps != hallucinated_steps {
+                        let why = InvalidMove::Mismatch {
+                            hallucinated: (
+                                self.board.expand(&hallucinated_state),
+                                hallucinated_steps.len() - 1,
+                            ),
+                            reached: (self.board.expand(&reached), steps.len() - 1),
+                        };
+                        self.record_invalid(state, mgr, input, why, mutator.clone())?;
+                    }
+                    // carry on from where the moves really lead
+                    hallucinated = Some((steps, reached));
+                }
+            }
+        }
+
+        let replayed = match hallucinated {
+            Some(hallucinated) => Ok(hallucinated),
+            None => self
+                .board
+                .trajectory(&self.initial, input.moves())
+                .ok_or_else(|| self.board.replay(&self.initial, input.moves()).unwrap_err()),
+        };
+
+        match replayed {
+            Ok((steps, current)) => {
+                if let Ok(stats) = state.metadata_mut::<CampaignStatsMetadata>() {
+                    stats.record_execution(steps.len() - 1, current.state_hash(&self.board, false));
+                }
+                if let Some(name) = self.trajectory_observer_name.as_ref() {
+                    self.observers
+                        .match_name_mut::<SokobanTrajectoryObserver>(name)
+                        .unwrap()
+                        .replace(steps);
+                }
+                let sokoban_observer = self
+                    .observers
+                    .match_name_mut::<SokobanStateObserver>(&self.state_observer_name)
+                    .unwrap();
+                sokoban_observer.replace(current);
+                Ok(ExitKind::Ok)
+            }
+            Err((index, board)) => {
+                let why = InvalidMove::Illegal {
+                    index,
+                    board: self.board.expand(&board),
+                };
+                self.record_invalid(state, mgr, input, why, mutator)?;
+                Ok(ExitKind::Crash)
+            }
+        }
+    }
+}
+
+impl<OT, S> UsesObservers for SokobanExecutor<OT, S>
+where
+    OT: ObserversTuple<Self::State>,
+    S: State<Input = SokobanInput>,
+{
+    type Observers = OT;
+}
+
+impl<OT, S> HasObservers for SokobanExecutor<OT, S>
+where
+    OT: ObserversTuple<Self::State>,
+    S: State<Input = SokobanInput>,
+{
+    fn observers(&self) -> &Self::Observers {
+        &self.observers
+    }
+
+    fn observers_mut(&mut self) -> &mut Self::Observers {
+        &mut self.observers
+    }
+}
+
+#[derive(Debug)]
+pub struct ReverseSokobanExecutor<OT, S> {
+    board: Arc<StaticBoard>,
+    solved: SokobanState,
+    observers: OT,
+    state_observer_name: String,
+    phantom: PhantomData<S>,
+}
+
+impl<OT, S> ReverseSokobanExecutor<OT, S>
+where
+    OT: SokobanObserversTuple,
+{
+    pub fn new(board: Arc<StaticBoard>, solved: SokobanState, observers: OT) -> Self {
+        Self {
+            board,
+            solved,
+            state_observer_name: observers.sokoban_observer_name().to_string(),
+            observers,
+            phantom: PhantomData,
         }
+    }
+}
 
-        if let Some(current) = hallucinated.or_else(|| {
-            input
-                .moves()
-                .iter()
-                .cloned()
-                .try_fold(self.initial.clone(), |state, dir| state.move_player(dir))
-                .ok()
-        }) {
+impl<OT, S> UsesState for ReverseSokobanExecutor<OT, S>
+where
+    S: State<Input = ReverseSokobanInput>,
+{
+    type State = S;
+}
+
+impl<EM, OT, S, Z> Executor<EM, Z> for ReverseSokobanExecutor<OT, S>
+where
+    EM: UsesState<State = Self::State>,
+    OT: ObserversTuple<S> + SokobanObserversTuple + Debug,
+    S: State<Input = ReverseSokobanInput> + HasExecutions + Debug,
+    Z: UsesState<State = Self::State>,
+{
+    fn run_target(
+        &mut self,
+        _fuzzer: &mut Z,
+        state: &mut Self::State,
+        _mgr: &mut EM,
+        input: &Self::Input,
+    ) -> Result<ExitKind, Error> {
+        *state.executions_mut() += 1;
+
+        if let Some(current) = input.replay(&self.solved) {
             let sokoban_observer = self
                 .observers
                 .match_name_mut::<SokobanStateObserver>(&self.state_observer_name)
                 .unwrap();
-            sokoban_observer.replace(current);
+            sokoban_observer.replace(self.board.compact(&current));
             Ok(ExitKind::Ok)
         } else {
             Ok(ExitKind::Crash)
@@ -92,18 +270,18 @@ where
     }
 }
 
-impl<OT, S> UsesObservers for SokobanExecutor<OT, S>
+impl<OT, S> UsesObservers for ReverseSokobanExecutor<OT, S>
 where
     OT: ObserversTuple<Self::State>,
-    S: State<Input = SokobanInput>,
+    S: State<Input = ReverseSokobanInput>,
 {
     type Observers = OT;
 }
 
-impl<OT, S> HasObservers for SokobanExecutor<OT, S>
+impl<OT, S> HasObservers for ReverseSokobanExecutor<OT, S>
 where
     OT: ObserversTuple<Self::State>,
-    S: State<Input = SokobanInput>,
+    S: State<Input = ReverseSokobanInput>,
 {
     fn observers(&self) -> &Self::Observers {
         &self.observers
@@ -113,3 +291,159 @@ where
         &mut self.observers
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::board::StaticBoard;
+    use crate::executor::SokobanExecutor;
+    use crate::feedback::SokobanRevisitFeedback;
+    use crate::input::SokobanInput;
+    use crate::observer::{SokobanStateObserver, SokobanTrajectoryObserver};
+    use crate::stage::HallucinationExecutor;
+    use crate::state::{
+        InitialPuzzleMetadata, InvalidInputsMetadata, InvalidMove, InvalidMoveMetadata,
+        PrefixCacheMetadata,
+    };
+    use libafl::corpus::{Corpus, InMemoryCorpus};
+    use libafl::events::NopEventManager;
+    use libafl::executors::{Executor, ExitKind, HasObservers};
+    use libafl::feedbacks::Feedback;
+    use libafl::state::{HasMetadata, StdState};
+    use libafl_bolts::rands::StdRand;
+    use libafl_bolts::tuples::{tuple_list, MatchName};
+    use sokoban::Direction::{Down, Left, Right, Up};
+    use sokoban::State as SokobanState;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_wrong_hallucination_is_recorded() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#######
+#_____#
+#_xm._#
+#_____#
+#######
+"#[..],
+        )
+        .unwrap();
+        let board = Arc::new(StaticBoard::new(&puzzle));
+        let initial = board.compact(&puzzle);
+        let observers = tuple_list!(SokobanStateObserver::new("state", board.clone(), true));
+        let mut executor =
+            SokobanExecutor::new(board.clone(), initial.clone(), observers).with_verification();
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<SokobanInput>::new(),
+            InMemoryCorpus::new(),
+            &mut (),
+            &mut (),
+        )
+        .unwrap();
+        state.add_metadata(InitialPuzzleMetadata::new(puzzle));
+        state.add_metadata(PrefixCacheMetadata::default());
+        state.add_metadata(InvalidInputsMetadata::default());
+        let mut mgr = NopEventManager::new();
+        let mut fuzzer = NopEventManager::new();
+
+        // the mutator claims that pushing the crate leaves everything where it was
+        executor.hallucinate("wrong", Some((vec![initial.clone()], initial.clone())));
+        let input = SokobanInput::new(vec![Right]);
+        let exit = executor
+            .run_target(&mut fuzzer, &mut state, &mut mgr, &input)
+            .unwrap();
+        assert_eq!(exit, ExitKind::Ok);
+
+        // and this one didn't notice the wall
+        executor.hallucinate("illegal", None);
+        let input = SokobanInput::new(vec![Up, Up, Left]);
+        let exit = executor
+            .run_target(&mut fuzzer, &mut state, &mut mgr, &input)
+            .unwrap();
+        assert_eq!(exit, ExitKind::Crash);
+
+        let invalid = state.metadata::<InvalidInputsMetadata>().unwrap().corpus();
+        assert_eq!(invalid.count(), 2);
+        let mut whys = invalid.ids().map(|id| {
+            let testcase = invalid.get(id).unwrap().borrow();
+            testcase.metadata::<InvalidMoveMetadata>().unwrap().clone()
+        });
+
+        let mismatch = whys.next().unwrap();
+        assert_eq!(mismatch.mutator(), Some("wrong"));
+        let InvalidMove::Mismatch {
+            hallucinated: (_, 0),
+            reached: (reached, 1),
+        } = mismatch.why()
+        else {
+            panic!("expected a mismatch, got {:?}", mismatch.why());
+        };
+        assert!(reached.in_solution_state());
+
+        let illegal = whys.next().unwrap();
+        assert_eq!(illegal.mutator(), Some("illegal"));
+        assert!(matches!(
+            illegal.why(),
+            InvalidMove::Illegal { index: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn test_revisit_found_along_hallucination() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#######
+#_____#
+#_xm_.#
+#_____#
+#######
+"#[..],
+        )
+        .unwrap();
+        let board = Arc::new(StaticBoard::new(&puzzle));
+        let initial = board.compact(&puzzle);
+        let trajectory = SokobanTrajectoryObserver::new("trajectory", board.clone());
+        let mut feedback = SokobanRevisitFeedback::new(&trajectory);
+        let observers = tuple_list!(
+            SokobanStateObserver::new("state", board.clone(), true),
+            trajectory
+        );
+        let mut executor = SokobanExecutor::new(board.clone(), initial.clone(), observers)
+            .with_trajectory("trajectory");
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<SokobanInput>::new(),
+            InMemoryCorpus::new(),
+            &mut (),
+            &mut (),
+        )
+        .unwrap();
+        state.add_metadata(InitialPuzzleMetadata::new(puzzle));
+        let mut mgr = NopEventManager::new();
+        let mut fuzzer = NopEventManager::new();
+
+        // the second input walks around the crate and pushes it back to where it started
+        for (moves, revisits) in [
+            (vec![Right], false),
+            (vec![Right, Up, Right, Right, Down, Left], true),
+        ] {
+            let input = SokobanInput::new(moves);
+            executor.hallucinate("test", board.trajectory(&initial, input.moves()));
+            let exit = executor
+                .run_target(&mut fuzzer, &mut state, &mut mgr, &input)
+                .unwrap();
+            assert_eq!(exit, ExitKind::Ok);
+
+            let steps = executor
+                .observers()
+                .match_name::<SokobanTrajectoryObserver>("trajectory")
+                .unwrap()
+                .trajectory();
+            assert_eq!(steps.len(), if revisits { 3 } else { 2 });
+            let interesting = feedback
+                .is_interesting(&mut state, &mut mgr, &input, executor.observers(), &exit)
+                .unwrap();
+            assert_eq!(interesting, !revisits);
+        }
+    }
+}
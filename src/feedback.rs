@@ -1,17 +1,27 @@
+use crate::board::CompactState;
 use crate::input::SokobanInput;
-use crate::observer::SokobanStateObserver;
-use crate::util::find_crates;
+use crate::observer::{SokobanStateObserver, SokobanTrajectoryObserver};
+use crate::state::{
+    CampaignStatsMetadata, FilledTargetsMetadata, InitialPuzzleMetadata, NewTargetsMetadata,
+    ReverseStatesMetadata, SolutionCostMetadata, StatePathsMetadata,
+};
+use crate::subgoal::{SubgoalReachedMetadata, SubgoalsMetadata};
+use crate::util::{
+    can_go_to, count_filled, crate_distance, filled_targets, find_crates, is_dead_square, is_frozen,
+};
+use libafl::corpus::Testcase;
 use libafl::events::{Event, EventFirer};
 use libafl::executors::ExitKind;
 use libafl::feedbacks::Feedback;
 use libafl::monitors::{UserStats, UserStatsValue};
-use libafl::observers::ObserversTuple;
+use libafl::observers::{ObserverWithHashField, ObserversTuple};
 use libafl::prelude::AggregatorOps;
-use libafl::state::State;
+use libafl::state::{HasMetadata, State};
 use libafl::Error;
 use libafl_bolts::Named;
-use sokoban::Direction::{Down, Left, Right, Up};
-use sokoban::Tile;
+use sokoban::{Direction, State as SokobanState};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct SokobanSolvedFeedback {
@@ -54,14 +64,351 @@ where
             .match_name::<SokobanStateObserver>(&self.obs_name)
             .unwrap();
 
-        if let Some(last_state) = state_obs.last_state() {
-            Ok(last_state.in_solution_state())
+        if let Some(last_state) = state_obs.last_compact() {
+            Ok(last_state.in_solution_state(state_obs.board()))
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SokobanMeetsReverseFeedback {
+    obs_name: String,
+    name: String,
+    meeting: Option<Vec<Direction>>,
+}
+
+impl SokobanMeetsReverseFeedback {
+    pub fn new(obs: &SokobanStateObserver) -> Self {
+        Self {
+            obs_name: obs.name().to_string(),
+            name: format!("meets_reverse_{}", obs.name()),
+            meeting: None,
+        }
+    }
+}
+
+impl Named for SokobanMeetsReverseFeedback {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<S> Feedback<S> for SokobanMeetsReverseFeedback
+where
+    S: State<Input = SokobanInput> + HasMetadata,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        let state_obs = observers
+            .match_name::<SokobanStateObserver>(&self.obs_name)
+            .unwrap();
+
+        self.meeting = state_obs.last_state().and_then(|last_state| {
+            state
+                .metadata::<ReverseStatesMetadata>()
+                .ok()?
+                .meet(last_state)
+        });
+        Ok(self.meeting.is_some())
+    }
+
+    // complete the input with the reverse path so that the stored solution solves the puzzle
+    fn append_metadata<OT>(
+        &mut self,
+        _state: &mut S,
+        _observers: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+    {
+        if let Some(meeting) = self.meeting.take() {
+            let mut moves = testcase.input().as_ref().unwrap().moves().to_vec();
+            moves.extend(meeting);
+            testcase.set_input(SokobanInput::new(moves));
+        }
+        Ok(())
+    }
+
+    fn discard_metadata(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.meeting = None;
+        Ok(())
+    }
+}
+
+// like NewHashFeedback, but a known state is interesting again when reached in fewer moves
+#[derive(Debug)]
+pub struct SokobanShorterPathFeedback {
+    obs_name: String,
+    name: String,
+}
+
+impl SokobanShorterPathFeedback {
+    pub fn new(obs: &SokobanStateObserver) -> Self {
+        Self {
+            obs_name: obs.name().to_string(),
+            name: format!("shorter_path_{}", obs.name()),
+        }
+    }
+}
+
+impl Named for SokobanShorterPathFeedback {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<S> Feedback<S> for SokobanShorterPathFeedback
+where
+    S: State<Input = SokobanInput> + HasMetadata,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        input: &S::Input,
+        observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        let state_obs = observers
+            .match_name::<SokobanStateObserver>(&self.obs_name)
+            .unwrap();
+
+        if let Some(hash) = state_obs.hash() {
+            Ok(state
+                .metadata_mut::<StatePathsMetadata>()?
+                .offer(hash, input.moves().len()))
         } else {
             Ok(false)
         }
     }
 }
 
+// treats each combination of filled targets like an edge in a coverage map
+#[derive(Debug)]
+pub struct SokobanFilledTargetsFeedback {
+    obs_name: String,
+    name: String,
+    novel: bool,
+}
+
+impl SokobanFilledTargetsFeedback {
+    pub fn new(obs: &SokobanStateObserver) -> Self {
+        Self {
+            obs_name: obs.name().to_string(),
+            name: format!("filled_targets_{}", obs.name()),
+            novel: false,
+        }
+    }
+}
+
+impl Named for SokobanFilledTargetsFeedback {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<S> Feedback<S> for SokobanFilledTargetsFeedback
+where
+    S: State + HasMetadata,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        let state_obs = observers
+            .match_name::<SokobanStateObserver>(&self.obs_name)
+            .unwrap();
+
+        self.novel = if let Some(last_state) = state_obs.last_state() {
+            state
+                .metadata_mut::<FilledTargetsMetadata>()?
+                .insert(filled_targets(last_state))
+        } else {
+            false
+        };
+        Ok(self.novel)
+    }
+
+    // so that the scheduler can favour it
+    fn append_metadata<OT>(
+        &mut self,
+        _state: &mut S,
+        _observers: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+    {
+        if std::mem::take(&mut self.novel) {
+            testcase.add_metadata(NewTargetsMetadata);
+        }
+        Ok(())
+    }
+
+    fn discard_metadata(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.novel = false;
+        Ok(())
+    }
+}
+
+// rejects inputs which return to an earlier crate configuration with nothing gained, i.e. where
+// the player could have just walked to where they ended up
+#[derive(Debug)]
+pub struct SokobanRevisitFeedback {
+    obs_name: String,
+    name: String,
+}
+
+impl SokobanRevisitFeedback {
+    pub fn new(obs: &SokobanTrajectoryObserver) -> Self {
+        Self {
+            obs_name: obs.name().to_string(),
+            name: format!("revisits_{}", obs.name()),
+        }
+    }
+}
+
+impl Named for SokobanRevisitFeedback {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<S> Feedback<S> for SokobanRevisitFeedback
+where
+    S: State,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        let trajectory_obs = observers
+            .match_name::<SokobanTrajectoryObserver>(&self.obs_name)
+            .unwrap();
+
+        // only expand a state once a later one shares its crates
+        let board = trajectory_obs.board();
+        let mut seen: HashMap<&[u64], Vec<(&CompactState, Option<SokobanState>)>> = HashMap::new();
+        for step in trajectory_obs.trajectory() {
+            let earlier = seen.entry(step.crates()).or_default();
+            for (compact, expanded) in earlier.iter_mut() {
+                let expanded = expanded.get_or_insert_with(|| board.expand(compact));
+                if can_go_to(expanded.player(), step.player(board), expanded) {
+                    return Ok(false);
+                }
+            }
+            earlier.push((step, None));
+        }
+        Ok(true)
+    }
+}
+
+// keeps whatever reaches the current sub-goal, so that the campaign can continue from there
+#[derive(Debug)]
+pub struct SokobanSubgoalFeedback {
+    obs_name: String,
+    name: String,
+    reached: Option<usize>,
+}
+
+impl SokobanSubgoalFeedback {
+    pub fn new(obs: &SokobanStateObserver) -> Self {
+        Self {
+            obs_name: obs.name().to_string(),
+            name: format!("subgoal_{}", obs.name()),
+            reached: None,
+        }
+    }
+}
+
+impl Named for SokobanSubgoalFeedback {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<S> Feedback<S> for SokobanSubgoalFeedback
+where
+    S: State + HasMetadata,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        let state_obs = observers
+            .match_name::<SokobanStateObserver>(&self.obs_name)
+            .unwrap();
+
+        self.reached = state_obs.last_state().and_then(|last_state| {
+            let (goal, subgoal) = state.metadata::<SubgoalsMetadata>().ok()?.current()?;
+            subgoal.reached(last_state).then_some(goal)
+        });
+        Ok(self.reached.is_some())
+    }
+
+    fn append_metadata<OT>(
+        &mut self,
+        state: &mut S,
+        _observers: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+    {
+        if let Some(goal) = self.reached.take() {
+            testcase.add_metadata(SubgoalReachedMetadata::new(goal));
+            state.metadata_mut::<SubgoalsMetadata>()?.mark_reached();
+        }
+        Ok(())
+    }
+
+    fn discard_metadata(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.reached = None;
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct SokobanSolvableFeedback {
     obs_name: String,
@@ -85,11 +432,11 @@ impl Named for SokobanSolvableFeedback {
 
 impl<S> Feedback<S> for SokobanSolvableFeedback
 where
-    S: State,
+    S: State + HasMetadata,
 {
     fn is_interesting<EM, OT>(
         &mut self,
-        _state: &mut S,
+        state: &mut S,
         _manager: &mut EM,
         _input: &S::Input,
         observers: &OT,
@@ -105,38 +452,23 @@ where
 
         if let Some(last_state) = state_obs.last_state() {
             let crates = find_crates(last_state);
-            for maybe_cornered in crates {
-                if !last_state.targets().contains(&maybe_cornered) {
-                    // we assume we are within the appropriate bounds
-                    if let Some(above) = Up.go(maybe_cornered) {
-                        if last_state[above] == Tile::Wall {
-                            if let Some(left) = Left.go(maybe_cornered) {
-                                if last_state[left] == Tile::Wall {
-                                    return Ok(false);
-                                }
-                            }
-                            if let Some(right) = Right.go(maybe_cornered) {
-                                if last_state[right] == Tile::Wall {
-                                    return Ok(false);
-                                }
-                            }
-                        }
-                    }
-                    if let Some(below) = Down.go(maybe_cornered) {
-                        if last_state[below] == Tile::Wall {
-                            if let Some(left) = Left.go(maybe_cornered) {
-                                if last_state[left] == Tile::Wall {
-                                    return Ok(false);
-                                }
-                            }
-                            if let Some(right) = Right.go(maybe_cornered) {
-                                if last_state[right] == Tile::Wall {
-                                    return Ok(false);
-                                }
-                            }
-                        }
-                    }
+            let deadlock = if crates
+                .iter()
+                .any(|&maybe_cornered| is_dead_square(last_state, maybe_cornered))
+            {
+                Some("dead_square")
+            } else if crates.iter().any(|&maybe_frozen| {
+                !last_state.targets().contains(&maybe_frozen) && is_frozen(last_state, maybe_frozen)
+            }) {
+                Some("freeze")
+            } else {
+                None
+            };
+            if let Some(rule) = deadlock {
+                if let Ok(stats) = state.metadata_mut::<CampaignStatsMetadata>() {
+                    stats.record_deadlock(rule);
                 }
+                return Ok(false);
             }
             Ok(true)
         } else {
@@ -149,6 +481,7 @@ where
 pub struct SokobanStatisticsFeedback {
     most_set: usize,
     most_moves: usize,
+    best_distance: usize,
     obs_name: String,
     name: String,
 }
@@ -158,6 +491,7 @@ impl SokobanStatisticsFeedback {
         Self {
             most_set: 0,
             most_moves: 0,
+            best_distance: usize::MAX,
             obs_name: obs.name().to_string(),
             name: format!("stats_{}", obs.name()),
         }
@@ -191,11 +525,7 @@ where
             .unwrap();
 
         if let Some(last_state) = state_obs.last_state() {
-            let most_set = last_state
-                .targets()
-                .iter()
-                .filter(|&&target| last_state[target] == Tile::Crate)
-                .count();
+            let most_set = count_filled(last_state);
             if most_set > self.most_set {
                 manager.fire(
                     state,
@@ -213,6 +543,21 @@ where
                 )?;
                 self.most_set = most_set;
             }
+            let distance = crate_distance(last_state);
+            if distance < self.best_distance {
+                manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: "best_distance".to_string(),
+                        value: UserStats::new(
+                            UserStatsValue::Number(distance as u64),
+                            AggregatorOps::Min,
+                        ),
+                        phantom: Default::default(),
+                    },
+                )?;
+                self.best_distance = distance;
+            }
             if input.moves().len() > self.most_moves {
                 manager.fire(
                     state,
@@ -231,3 +576,65 @@ where
         Ok(true)
     }
 }
+
+// stamps new solutions with their cost, including when they were found; goes last in the
+// objective so that it sees the completed input
+#[derive(Debug)]
+pub struct SolutionCostFeedback {
+    start: Instant,
+    offset: Duration,
+}
+
+impl SolutionCostFeedback {
+    // the offset is the time spent before a resume
+    pub fn new(start: Instant, offset: Duration) -> Self {
+        Self { start, offset }
+    }
+}
+
+impl Named for SolutionCostFeedback {
+    fn name(&self) -> &str {
+        "solution_cost"
+    }
+}
+
+impl<S> Feedback<S> for SolutionCostFeedback
+where
+    S: State<Input = SokobanInput> + HasMetadata,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &S::Input,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<State = S>,
+        OT: ObserversTuple<S>,
+    {
+        Ok(true)
+    }
+
+    fn append_metadata<OT>(
+        &mut self,
+        state: &mut S,
+        _observers: &OT,
+        testcase: &mut Testcase<S::Input>,
+    ) -> Result<(), Error>
+    where
+        OT: ObserversTuple<S>,
+    {
+        let initial = state.metadata::<InitialPuzzleMetadata>()?;
+        let moves = testcase.input().as_ref().unwrap().moves();
+        let pushes = initial
+            .board()
+            .count_pushes(initial.compact(), moves)
+            .ok_or_else(|| Error::illegal_state("solution contains an illegal move"))?;
+        let cost =
+            SolutionCostMetadata::new(moves.len(), pushes, self.offset + self.start.elapsed());
+        testcase.add_metadata(cost);
+        Ok(())
+    }
+}
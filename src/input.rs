@@ -1,34 +1,88 @@
-use crate::state::{InitialPuzzleMetadata, LastHallucinationMetadata};
-use libafl::corpus::{CorpusId, Testcase};
+use crate::board::{CompactState, StaticBoard};
+use crate::state::InitialPuzzleMetadata;
+use crate::util::{lurd, opposite, parse_lurd, pull_player, with_player};
+use libafl::corpus::{Corpus, CorpusId};
 use libafl::inputs::Input;
 use libafl::prelude::HasCorpus;
-use libafl::stages::mutational::MutatedTransform;
 use libafl::state::HasMetadata;
 use libafl::Error;
+use libafl_bolts::fs::write_file_atomic;
 use serde::{Deserialize, Serialize};
 use sokoban::{Direction, State as SokobanState};
-use std::ops::DerefMut;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::OnceLock;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SokobanInput {
     moves: Vec<Direction>,
 }
 
+// on-disk corpora name their files after the input, which only fits for so long; the file itself
+// always holds the whole path
+const MAX_NAME_LEN: usize = 200;
+
+// the puzzle the inputs are played on, to tell the pushes apart in their LURD
+static PUZZLE: OnceLock<SokobanState> = OnceLock::new();
+
+// every input of this process is played on the puzzle; only the first one set counts
+pub fn set_puzzle(puzzle: &SokobanState) {
+    let _ = PUZZLE.get_or_init(|| puzzle.clone());
+}
+
+impl SokobanInput {
+    // the pushes are in upper case, where we know the puzzle and the moves are legal on it
+    fn lurd(&self) -> String {
+        PUZZLE
+            .get()
+            .and_then(|puzzle| lurd(puzzle, &self.moves))
+            .unwrap_or_else(|| {
+                self.moves
+                    .iter()
+                    .map(|&direction| match direction {
+                        Direction::Left => 'l',
+                        Direction::Up => 'u',
+                        Direction::Right => 'r',
+                        Direction::Down => 'd',
+                    })
+                    .collect()
+            })
+    }
+}
+
 impl Input for SokobanInput {
+    // in LURD, so that the files can be replayed elsewhere
+    fn to_file<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        write_file_atomic(path, self.lurd().as_bytes())
+    }
+
+    fn from_file<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let moves = parse_lurd(fs::read_to_string(path)?.trim()).ok_or_else(|| {
+            Error::illegal_argument(format!("{} doesn't hold LURD moves", path.display()))
+        })?;
+        Ok(Self::new(moves))
+    }
+
     fn generate_name(&self, _idx: usize) -> String {
-        String::from_utf8(
-            self.moves
-                .iter()
-                .cloned()
-                .map(|m| match m {
-                    Direction::Up => b'U',
-                    Direction::Down => b'D',
-                    Direction::Left => b'L',
-                    Direction::Right => b'R',
-                })
-                .collect::<Vec<_>>(),
-        )
-        .unwrap()
+        let name = self.lurd();
+        if name.is_empty() {
+            "empty".to_string()
+        } else if name.len() > MAX_NAME_LEN {
+            let mut hasher = DefaultHasher::new();
+            name.hash(&mut hasher);
+            format!("{}-{:016x}", &name[..MAX_NAME_LEN - 17], hasher.finish())
+        } else {
+            name
+        }
     }
 }
 
@@ -42,10 +96,80 @@ impl SokobanInput {
     }
 }
 
+// a sequence of reverse moves from a solved configuration; a pull drags the crate behind the
+// player along, undoing a push
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReverseSokobanInput {
+    start: (usize, usize),
+    moves: Vec<(Direction, bool)>,
+}
+
+impl Input for ReverseSokobanInput {
+    fn generate_name(&self, _idx: usize) -> String {
+        let moves = self
+            .moves
+            .iter()
+            .map(|&(m, pull)| {
+                let c = match m {
+                    Direction::Up => 'u',
+                    Direction::Down => 'd',
+                    Direction::Left => 'l',
+                    Direction::Right => 'r',
+                };
+                if pull {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                }
+            })
+            .collect::<String>();
+        format!("{}_{}_{moves}", self.start.0, self.start.1)
+    }
+}
+
+impl ReverseSokobanInput {
+    pub fn new(start: (usize, usize)) -> Self {
+        Self {
+            start,
+            moves: Vec::new(),
+        }
+    }
+
+    pub fn moves(&self) -> &[(Direction, bool)] {
+        &self.moves
+    }
+
+    pub fn moves_mut(&mut self) -> &mut Vec<(Direction, bool)> {
+        &mut self.moves
+    }
+
+    pub fn replay(&self, solved: &SokobanState) -> Option<SokobanState> {
+        self.moves.iter().try_fold(
+            with_player(solved, self.start)?,
+            |puzzle, &(direction, pull)| pull_player(puzzle, direction, pull),
+        )
+    }
+
+    // the forward moves which undo this input, i.e. solve the puzzle from the state it reaches
+    pub fn forward_moves(&self) -> Vec<Direction> {
+        self.moves
+            .iter()
+            .rev()
+            .map(|&(direction, _)| opposite(direction))
+            .collect()
+    }
+}
+
+// the hallucination stays compact; mutators plan on it and play their moves on it directly,
+// keeping the state after every push as they go
 #[derive(Clone, Deserialize, Serialize)]
 pub struct HallucinatedSokobanInput {
-    hallucinated: Option<SokobanState>,
+    hallucinated: Option<CompactState>,
+    // the state before the first push and after every push, like StaticBoard::trajectory
+    trajectory: Vec<CompactState>,
     moves: Vec<Direction>,
+    // a mutator played an illegal move, so the hallucination no longer holds
+    illegal: bool,
 }
 
 impl HallucinatedSokobanInput {
@@ -53,55 +177,70 @@ impl HallucinatedSokobanInput {
         &self.moves
     }
 
-    pub fn hallucinated_mut(&mut self) -> &mut Option<SokobanState> {
-        &mut self.hallucinated
+    pub fn hallucinated(&self) -> &CompactState {
+        self.hallucinated
+            .as_ref()
+            .expect("Contract violated; mutator failed to return hallucination.")
     }
 
-    pub fn moves_mut(&mut self) -> &mut Vec<Direction> {
-        &mut self.moves
+    // like hallucinated, for callers that may see an input before any mutator has run on it
+    pub fn try_hallucinated(&self) -> Option<&CompactState> {
+        self.hallucinated.as_ref()
     }
-}
 
-impl<S> MutatedTransform<SokobanInput, S> for HallucinatedSokobanInput
-where
-    S: HasCorpus<Input = SokobanInput> + HasMetadata,
-{
-    type Post = ();
-
-    fn try_transform_from(
-        base: &mut Testcase<SokobanInput>,
-        state: &S,
-        _corpus_idx: CorpusId,
-    ) -> Result<Self, Error> {
-        let hallucinated = state
-            .metadata::<InitialPuzzleMetadata>()
-            .unwrap()
-            .initial()
-            .clone();
-        let input = base.load_input(state.corpus())?;
-        let hallucinated = input
-            .moves()
-            .iter()
-            .copied()
-            .try_fold(hallucinated, |puzzle, direction| {
-                puzzle.move_player(direction)
-            })
+    // keeps only the first len moves, which reach the given state right after the given pushes
+    pub fn truncate(&mut self, len: usize, hallucinated: CompactState, pushes: usize) {
+        self.moves.truncate(len);
+        self.hallucinated = Some(hallucinated);
+        self.trajectory.truncate(pushes + 1);
+        self.illegal = false;
+    }
+
+    // appends the moves and plays them on the hallucination; once a move is illegal, the rest are
+    // only appended, and the executor finds out where it went wrong
+    pub fn play(&mut self, board: &StaticBoard, moves: impl IntoIterator<Item = Direction>) {
+        let hallucinated = self
+            .hallucinated
+            .as_mut()
+            .expect("Contract violated; mutator failed to return hallucination.");
+        for direction in moves {
+            if !self.illegal {
+                match hallucinated.move_player(board, direction) {
+                    Some(true) => self.trajectory.push(hallucinated.clone()),
+                    Some(false) => {}
+                    None => self.illegal = true,
+                }
+            }
+            self.moves.push(direction);
+        }
+    }
+
+    pub fn from_corpus<S>(idx: CorpusId, state: &mut S) -> Result<Self, Error>
+    where
+        S: HasCorpus<Input = SokobanInput> + HasMetadata,
+    {
+        let input = state.corpus().cloned_input_for_id(idx)?;
+        let initial = state.metadata::<InitialPuzzleMetadata>()?;
+        let (trajectory, hallucinated) = initial
+            .board()
+            .trajectory(initial.compact(), input.moves())
             .expect("Invalid sequence of moves while performing transform!");
 
         Ok(Self {
             hallucinated: Some(hallucinated),
-            moves: input.moves.clone(),
+            trajectory,
+            moves: input.moves,
+            illegal: false,
         })
     }
 
-    fn try_transform_into(self, state: &S) -> Result<(SokobanInput, Self::Post), Error> {
-        let metadata = state.metadata::<LastHallucinationMetadata>()?;
-
-        metadata.hallucination_mut().deref_mut().replace(
-            self.hallucinated
-                .expect("Contract violated; mutator failed to return hallucination."),
-        );
-
-        Ok((SokobanInput::new(self.moves), ()))
+    // the input to execute, and the states it is known to pass through and reach, unless a mutator
+    // played an illegal move
+    pub fn into_parts(self) -> (SokobanInput, Option<(Vec<CompactState>, CompactState)>) {
+        let hallucinated = self
+            .hallucinated
+            .expect("Contract violated; mutator failed to return hallucination.");
+        let hallucination = (!self.illegal).then_some((self.trajectory, hallucinated));
+        (SokobanInput::new(self.moves), hallucination)
     }
 }
@@ -1,46 +1,325 @@
-use libafl::corpus::HasTestcase;
-use libafl::monitors::{AggregatorOps, SimplePrintingMonitor, UserStatsValue};
+use clap::{Parser, ValueEnum};
+use libafl::events::{
+    EventConfig, EventProcessor, EventRestarter, Launcher, LlmpRestartingEventManager,
+    ProgressReporter,
+};
+use libafl::feedbacks::{EagerOrFeedback, FastAndFeedback, FastOrFeedback};
+use libafl::monitors::{AggregatorOps, SimpleMonitor, UserStatsValue};
+use libafl::stages::IfElseStage;
 use libafl::state::HasExecutions;
 use libafl::{
-    corpus::{Corpus, InMemoryCorpus},
-    events::Event::{Objective, UpdateUserStats},
+    corpus::{CachedOnDiskCorpus, Corpus, HasTestcase, InMemoryCorpus, OnDiskCorpus, Testcase},
+    events::Event::{NewTestcase, Objective, UpdateUserStats},
     events::{EventFirer, SimpleEventManager},
-    feedback_and_fast,
-    feedbacks::NewHashFeedback,
-    monitors::{Monitor, SimpleMonitor, UserStats},
-    stages::StdMutationalStage,
+    executors::ExitKind,
+    feedback_and_fast, feedback_or, feedback_or_fast,
+    monitors::UserStats,
     state::{HasCorpus, HasMaxSize, HasMetadata, HasSolutions, StdState},
     Error, Evaluator, Fuzzer, StdFuzzer,
 };
-use libafl_bolts::rands::{RandomSeed, RomuDuoJrRand, StdRand};
+use libafl_bolts::core_affinity::{get_core_ids, CoreId, Cores};
+use libafl_bolts::rands::{RomuDuoJrRand, StdRand};
+use libafl_bolts::shmem::{ShMemProvider, StdShMemProvider};
 use libafl_bolts::tuples::tuple_list;
+use libafl_bolts::{current_nanos, current_time};
+use libafl_bolts::{AsMutSlice, AsSlice, Named};
 use serde::{Deserialize, Serialize};
-use sokoban::{State as SokobanState, Tile};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use sokoban::{Direction, State as SokobanState, Tile};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fmt::Debug;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::str::FromStr;
-use std::time::Duration;
-use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::http::Uri;
-use tokio_tungstenite::tungstenite::{connect, ClientRequestBuilder, Message, Utf8Bytes};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::{connect, Message, Utf8Bytes};
 
+use crate::budget::{exhausted_budget, BudgetMetadata};
 use crate::executor::SokobanExecutor;
-use crate::feedback::{SokobanSolvableFeedback, SokobanSolvedFeedback, SokobanStatisticsFeedback};
-use crate::input::SokobanInput;
-use crate::mutators::{MoveCrateMutator, MoveCrateToTargetMutator, OneShotMutator};
-use crate::observer::SokobanStateObserver;
+use crate::feedback::{
+    SokobanFilledTargetsFeedback, SokobanMeetsReverseFeedback, SokobanRevisitFeedback,
+    SokobanShorterPathFeedback, SokobanSolvableFeedback, SokobanSolvedFeedback,
+    SokobanStatisticsFeedback, SokobanSubgoalFeedback, SolutionCostFeedback,
+};
+use crate::input::{set_puzzle, SokobanInput};
+use crate::mutators::{
+    MoveCrateMutator, MoveCrateToTargetMutator, MutatorSuccessMetadata, OneShotMutator,
+    PushScoresMetadata, RandomPreferenceMutator, SokobanRemainingMutationsMetadata, SpliceMutator,
+    StraightPushMutator, TrackedMutator, TruncateMutator,
+};
+use crate::observer::{SokobanStateObserver, SokobanTrajectoryObserver};
+use crate::persist::{
+    corpus_files, load_checkpoint, load_puzzle, save_checkpoint, save_corpus_metadata, save_puzzle,
+    save_testcase_metadata, Checkpoint, SavedCampaign, PUZZLE_FILE, QUEUE_DIR, SOLUTIONS_DIR,
+};
+use crate::reverse::{ReverseCampaign, ReverseCheckpoint};
 use crate::scheduler::SokobanWeightScheduler;
-use crate::state::{InitialPuzzleMetadata, LastHallucinationMetadata};
+use crate::stage::HallucinatingStage;
+use crate::state::{
+    best_partial, settled_solution, update_solution_front, CampaignStatsMetadata, FavouredMetadata,
+    FilledTargetsMetadata, InitialPuzzleMetadata, InvalidInputsMetadata, InvalidMove,
+    InvalidMoveMetadata, PartialProgress, PrefixCacheMetadata, ReverseStatesMetadata,
+    SolutionCostMetadata, StatePathsMetadata,
+};
+use crate::subgoal::{advance_subgoal, Subgoal, SubgoalsMetadata};
+use crate::util::{count_pushes, find_crates, lurd, reroute_walks, trajectory};
 
+mod board;
+mod budget;
 mod executor;
 mod feedback;
 mod input;
 mod mutators;
 mod observer;
+mod persist;
+mod reverse;
 mod scheduler;
+mod stage;
 mod state;
+mod subgoal;
 mod util;
 
+#[derive(Debug, Parser)]
+#[command(about = "Solve sokoban puzzles by fuzzing them")]
+struct Opt {
+    /// Read the level from this file instead of fetching it from the server
+    #[arg(long)]
+    level: Option<PathBuf>,
+    /// Don't stream progress to the web viewer
+    #[arg(long)]
+    no_viewer: bool,
+    /// Pick mutators by their observed success instead of running each in its own stage
+    #[arg(long)]
+    adaptive: bool,
+    /// Also search backwards from the solved puzzle and join up with the forward search
+    #[arg(long)]
+    reverse: bool,
+    /// Keep searching after the first solution for one with fewer moves or fewer pushes
+    #[arg(long, value_enum)]
+    minimize: Option<Metric>,
+    /// Executions to spend minimizing the solution
+    #[arg(long, default_value_t = 100_000)]
+    minimize_budget: usize,
+    /// Also weigh how early a solution was found when deciding which solutions to keep
+    #[arg(long)]
+    pareto_time: bool,
+    /// Write the solutions that are best by moves or by pushes to this file as JSON
+    #[arg(long)]
+    front: Option<PathBuf>,
+    /// Write the states along the solution, one per push, out to this file as JSON
+    #[arg(long)]
+    export_trajectory: Option<PathBuf>,
+    /// Also append the statistics to this file as CSV, one row per statistic per report
+    #[arg(long)]
+    stats_csv: Option<PathBuf>,
+    /// Reach these sub-goals in order before going for the solution: targets=r,c;r,c;... fills
+    /// the given targets, room=r,c;r,c clears every crate out of the rectangle between the corners
+    #[arg(long = "subgoal")]
+    subgoals: Vec<Subgoal>,
+    /// Check every state a mutator claims an input reaches against a replay of the input
+    #[arg(long)]
+    verify_hallucinations: bool,
+    /// Keep the corpus and solutions on disk in this directory, resuming from whatever an earlier
+    /// run left there; the entries are added again, so that resume is approximate: the shortest
+    /// paths to each state, the pending rebases and the random number generator start over, unless
+    /// resuming from a checkpoint
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Fuzz on these cores (e.g. 0-3,6 or all), one client each, sharing corpus entries and
+    /// solutions; every client stops searching as soon as any of them solves the level
+    #[arg(long, value_parser = Cores::from_cmdline)]
+    cores: Option<Cores>,
+    /// Port of the broker the clients share their findings through
+    #[arg(long, default_value_t = 1337)]
+    broker_port: u16,
+    /// Save the whole campaign to this file every so often, to carry on from with --resume; with
+    /// several cores, each client saves to its own file with its index appended; with --output,
+    /// resuming puts the directory back as it was at the checkpoint
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    /// Seconds between checkpoints
+    #[arg(long, default_value_t = 600)]
+    checkpoint_interval: u64,
+    /// Carry on from the checkpoint rather than starting over
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+    /// Print the result as a single JSON document at exit, and the usual report to stderr; with
+    /// several cores, the first client reports
+    #[arg(long)]
+    json: bool,
+    /// Seed the random number generator with this instead of the clock, so that a single-core
+    /// run can be repeated exactly; with several cores, each client adds its index
+    #[arg(long, conflicts_with = "resume")]
+    seed: Option<u64>,
+    /// Give up searching after this many executions, counting those of earlier runs on the same
+    /// checkpoint or output directory; with several cores, each client has its own budget
+    #[arg(long)]
+    max_executions: Option<usize>,
+    /// Give up searching after this many seconds, counting those before a resume
+    #[arg(long)]
+    max_time: Option<u64>,
+    /// Give up searching once the corpus holds this many entries
+    #[arg(long)]
+    max_corpus: Option<usize>,
+    /// Give up searching once the process takes up this many megabytes of memory
+    #[arg(long)]
+    max_memory: Option<u64>,
+}
+
+// the human-readable report, which moves to stderr when stdout is kept for the --json result
+macro_rules! report {
+    ($json:expr, $($arg:tt)*) => {
+        if $json {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Metric {
+    Moves,
+    Pushes,
+}
+
+impl Metric {
+    // the primary measure first, the other one as a tiebreaker
+    fn cost(self, cost: &SolutionCostMetadata) -> (usize, usize) {
+        match self {
+            Metric::Moves => (cost.moves(), cost.pushes()),
+            Metric::Pushes => (cost.pushes(), cost.moves()),
+        }
+    }
+}
+
+// how a campaign ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Solved,
+    // the corpus ran dry without reaching a solution
+    Unsolvable,
+    Interrupted,
+    // one of the budgets ran out first
+    Timeout,
+    Error,
+}
+
+impl Outcome {
+    const ALL: [Outcome; 5] = [
+        Outcome::Solved,
+        Outcome::Unsolvable,
+        Outcome::Interrupted,
+        Outcome::Timeout,
+        Outcome::Error,
+    ];
+
+    fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Solved => 0,
+            // what returning an error from main exits with
+            Outcome::Error => 1,
+            Outcome::Unsolvable => 3,
+            // like a shell reports a process killed by SIGINT
+            Outcome::Interrupted => 130,
+            // like timeout(1)
+            Outcome::Timeout => 124,
+        }
+    }
+
+    // how a campaign without a solution ended; being stopped wins over running out of budget
+    fn unsolved(stopped: bool, exhausted: bool) -> Self {
+        if stopped {
+            Outcome::Interrupted
+        } else if exhausted {
+            Outcome::Timeout
+        } else {
+            Outcome::Unsolvable
+        }
+    }
+
+    // as passed from the reporting client to the broker
+    fn from_u8(byte: u8) -> Option<Self> {
+        Outcome::ALL
+            .into_iter()
+            .find(|&outcome| outcome as u8 == byte)
+    }
+}
+
+// what a campaign ended with, for the --json result
+struct CampaignReport {
+    outcome: Outcome,
+    // the one we settled on first, then the rest of the front
+    solutions: Vec<Vec<Direction>>,
+    executions: usize,
+    seed: u64,
+    // how close we got, if we didn't solve it
+    best_partial: Option<PartialProgress>,
+}
+
+#[derive(Serialize)]
+struct JsonLevel {
+    source: String,
+    hash: String,
+    rows: usize,
+    cols: usize,
+    targets: usize,
+}
+
+#[derive(Serialize)]
+struct JsonSolution {
+    lurd: String,
+    moves: usize,
+    pushes: usize,
+}
+
+#[derive(Serialize)]
+struct JsonPartial {
+    lurd: String,
+    filled: usize,
+    targets: usize,
+    distance: usize,
+}
+
+#[derive(Serialize)]
+struct JsonResult {
+    level: Option<JsonLevel>,
+    status: Outcome,
+    solutions: Vec<JsonSolution>,
+    best_partial: Option<JsonPartial>,
+    executions: Option<usize>,
+    wall_secs: f64,
+    seed: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TrajectoryStep {
+    player: (usize, usize),
+    crates: Vec<(usize, usize)>,
+}
+
+#[derive(Serialize)]
+struct PartialEntry<'a> {
+    filled: usize,
+    targets: usize,
+    distance: usize,
+    moves: &'a [Direction],
+}
+
+#[derive(Serialize)]
+struct FrontEntry<'a> {
+    moves: usize,
+    pushes: usize,
+    found_secs: f64,
+    solution: &'a [Direction],
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 struct Response {
     deal: String,
@@ -96,7 +375,7 @@ fn parse_file(file: File) -> Result<SokobanState, std::io::Error> {
 
     for row in &mut rows {
         let needed = dim_c - row.len();
-        row.extend(std::iter::repeat(b' ').take(needed));
+        row.extend(std::iter::repeat_n(b' ', needed));
     }
 
     let raw = rows.into_iter().flatten().collect::<Vec<_>>();
@@ -134,128 +413,977 @@ fn parse_file(file: File) -> Result<SokobanState, std::io::Error> {
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let puzzle = reqwest::blocking::get("https://39c3.addisoncrump.info/sokoban/initial")?
-        .json::<SokobanState>()?;
-
-    // let monitor = TuiMonitor::new(TuiUI::new("sokoban-fuzz".to_string(), true));
-    // let monitor = SimpleMonitor::new(|_| {});
-    let monitor = SimplePrintingMonitor::new();
+    let opt = Opt::parse();
+    let start = Instant::now();
 
-    let mut mgr = SimpleEventManager::new(monitor);
+    let mut level = None;
+    let result = run(&opt, &mut level);
+    let (report, outcome) = match &result {
+        Ok((report, outcome)) => (report.as_ref(), *outcome),
+        Err(_) => (None, Outcome::Error),
+    };
+    if opt.json && (report.is_some() || result.is_err()) {
+        let solutions = match (report, &level) {
+            (Some(report), Some((puzzle, _))) => report
+                .solutions
+                .iter()
+                .map(|moves| JsonSolution {
+                    lurd: lurd(puzzle, moves).unwrap(),
+                    moves: moves.len(),
+                    pushes: count_pushes(puzzle, moves).unwrap(),
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let best_partial = match (report, &level) {
+            (Some(report), Some((puzzle, _))) => {
+                report.best_partial.as_ref().map(|best| JsonPartial {
+                    lurd: lurd(puzzle, &best.moves).unwrap(),
+                    filled: best.filled,
+                    targets: puzzle.targets().len(),
+                    distance: best.distance,
+                })
+            }
+            _ => None,
+        };
+        let result = JsonResult {
+            level: level.as_ref().map(|(puzzle, source)| {
+                let mut hasher = DefaultHasher::new();
+                puzzle.hash(&mut hasher);
+                JsonLevel {
+                    source: source.clone(),
+                    hash: format!("{:016x}", hasher.finish()),
+                    rows: puzzle.rows(),
+                    cols: puzzle.cols(),
+                    targets: puzzle.targets().len(),
+                }
+            }),
+            status: outcome,
+            solutions,
+            best_partial,
+            executions: report.map(|report| report.executions),
+            wall_secs: start.elapsed().as_secs_f64(),
+            seed: report.map(|report| report.seed),
+            error: result.as_ref().err().map(|error| error_message(&**error)),
+        };
+        println!("{}", serde_json::to_string(&result)?);
+    }
 
-    fuzz(&mut mgr, puzzle)?;
+    result?;
+    if outcome != Outcome::Solved {
+        std::process::exit(outcome.exit_code());
+    }
     Ok(())
 }
 
-type SokobanManager<M> = SimpleEventManager<
-    M,
-    StdState<
-        SokobanInput,
-        InMemoryCorpus<SokobanInput>,
-        RomuDuoJrRand,
-        InMemoryCorpus<SokobanInput>,
+// libafl appends the backtrace to the message, which the JSON result leaves out
+fn error_message(error: &dyn std::error::Error) -> String {
+    let message = error.to_string();
+    match message.split_once("\nBacktrace:") {
+        Some((message, _)) => message.to_string(),
+        None => message,
+    }
+}
+
+// the puzzle and where it came from are left in level as soon as they're known
+#[allow(clippy::type_complexity)]
+fn run(
+    opt: &Opt,
+    level: &mut Option<(SokobanState, String)>,
+) -> Result<(Option<CampaignReport>, Outcome), Box<dyn std::error::Error>> {
+    let saved = opt
+        .output
+        .as_deref()
+        .map(load_puzzle)
+        .transpose()?
+        .flatten();
+    let (puzzle, source) = if let Some(path) = &opt.level {
+        (parse_file(File::open(path)?)?, path.display().to_string())
+    } else if let Some(saved) = saved.clone() {
+        let output = opt.output.as_ref().unwrap();
+        (saved, output.join(PUZZLE_FILE).display().to_string())
+    } else {
+        (
+            reqwest::blocking::get(LEVEL_URL)?.json::<SokobanState>()?,
+            LEVEL_URL.to_string(),
+        )
+    };
+    *level = Some((puzzle.clone(), source));
+    if saved.is_some_and(|saved| saved != puzzle) {
+        return Err("the output directory belongs to a different level".into());
+    }
+
+    for subgoal in &opt.subgoals {
+        subgoal
+            .check(&puzzle)
+            .map_err(|e| format!("bad sub-goal {subgoal}: {e}"))?;
+    }
+
+    if let Some(output) = &opt.output {
+        save_puzzle(output, &puzzle)?;
+    }
+    set_puzzle(&puzzle);
+
+    match (&opt.cores, &opt.output) {
+        (Some(cores), Some(output)) => Ok(launch(opt, &puzzle, cores, |core| {
+            open_output(&output.join(format!("core-{}", core.0)), opt.resume)
+        })?),
+        (Some(cores), None) => Ok(launch(opt, &puzzle, cores, |_| {
+            Ok((
+                InMemoryCorpus::new(),
+                InMemoryCorpus::new(),
+                SavedCampaign::default(),
+            ))
+        })?),
+        (None, Some(output)) => {
+            let (corpus, solutions, saved) = open_output(output, opt.resume)?;
+            let mut mgr = SimpleEventManager::new(monitor(opt.json));
+            let report = fuzz(&mut mgr, puzzle, opt, corpus, solutions, saved, None)?;
+            let outcome = report.outcome;
+            Ok((Some(report), outcome))
+        }
+        (None, None) => {
+            // let monitor = TuiMonitor::new(TuiUI::new("sokoban-fuzz".to_string(), true));
+            // let monitor = SimpleMonitor::new(|_| {});
+            let mut mgr = SimpleEventManager::new(monitor(opt.json));
+            let report = fuzz(
+                &mut mgr,
+                puzzle,
+                opt,
+                InMemoryCorpus::new(),
+                InMemoryCorpus::new(),
+                SavedCampaign::default(),
+                None,
+            )?;
+            let outcome = report.outcome;
+            Ok((Some(report), outcome))
+        }
+    }
+}
+
+const LEVEL_URL: &str = "https://39c3.addisoncrump.info/sokoban/initial";
+
+fn monitor(json: bool) -> SimpleMonitor<impl FnMut(String) + Clone> {
+    SimpleMonitor::with_user_monitor(move |line| report!(json, "{line}"), true)
+}
+
+// entries of an on-disk corpus kept in memory
+const CORPUS_CACHE_SIZE: usize = 4096;
+
+type OnDiskCorpora = (
+    CachedOnDiskCorpus<SokobanInput>,
+    OnDiskCorpus<SokobanInput>,
+    SavedCampaign,
+);
+
+// the entries of a run resuming from a checkpoint come from the checkpoint instead
+fn open_output(dir: &Path, checkpointed: bool) -> Result<OnDiskCorpora, Error> {
+    let saved = if checkpointed {
+        SavedCampaign::attach(dir)
+    } else {
+        SavedCampaign::take(dir)?
+    };
+    let corpus = CachedOnDiskCorpus::new(dir.join(QUEUE_DIR), CORPUS_CACHE_SIZE)?;
+    let solutions = OnDiskCorpus::new(dir.join(SOLUTIONS_DIR))?;
+    Ok((corpus, solutions, saved))
+}
+
+type SokobanFuzzState<C, SC> = StdState<SokobanInput, C, RomuDuoJrRand, SC>;
+
+type SokobanObservers = (SokobanStateObserver, (SokobanTrajectoryObserver, ()));
+
+type SokobanFeedback<S> = FastAndFeedback<
+    SokobanSolvableFeedback,
+    FastAndFeedback<
+        SokobanRevisitFeedback,
+        FastAndFeedback<
+            EagerOrFeedback<
+                SokobanShorterPathFeedback,
+                EagerOrFeedback<SokobanFilledTargetsFeedback, SokobanSubgoalFeedback, S>,
+                S,
+            >,
+            SokobanStatisticsFeedback,
+            S,
+        >,
+        S,
     >,
+    S,
+>;
+
+type SokobanObjective<S> = FastAndFeedback<
+    FastOrFeedback<SokobanSolvedFeedback, SokobanMeetsReverseFeedback, S>,
+    SolutionCostFeedback,
+    S,
 >;
 
-fn fuzz(mgr: &mut SokobanManager<impl Monitor>, puzzle: SokobanState) -> Result<(), Error> {
-    let (mut ws, _) = connect("wss://39c3.addisoncrump.info/sokoban/play").unwrap();
-    let sokoban_obs = SokobanStateObserver::new("sokoban_state", true);
+type SokobanFuzzer<S> =
+    StdFuzzer<SokobanWeightScheduler<S>, SokobanFeedback<S>, SokobanObjective<S>, SokobanObservers>;
+
+// one client per core, each with its own corpora, talking through a broker in this process
+fn launch<C, SC>(
+    opt: &Opt,
+    puzzle: &SokobanState,
+    cores: &Cores,
+    corpora: impl Fn(CoreId) -> Result<(C, SC, SavedCampaign), Error>,
+) -> Result<(Option<CampaignReport>, Outcome), Error>
+where
+    C: Corpus<Input = SokobanInput> + HasTestcase + Debug,
+    SC: Corpus<Input = SokobanInput> + Debug,
+{
+    // the launcher skips cores that don't exist, and would then wait for them forever
+    let available = get_core_ids()?;
+    if let Some(core) = cores.ids.iter().find(|core| !available.contains(core)) {
+        return Err(Error::illegal_argument(format!(
+            "core {} isn't available",
+            core.0
+        )));
+    }
+
+    // the first client reports for everyone, and tells the broker how to exit through shared memory
+    let mut shmem = StdShMemProvider::new()?.new_shmem(1)?;
+    shmem.as_mut_slice()[0] = Outcome::Error as u8;
+    let mut report = None;
+    let run_client = |_state: Option<SokobanFuzzState<C, SC>>,
+                      mut mgr: LlmpRestartingEventManager<_, _>,
+                      core: CoreId| {
+        let (corpus, solutions, saved) = corpora(core)?;
+        let client = cores.position(core).unwrap();
+        let res = fuzz(
+            &mut mgr,
+            puzzle.clone(),
+            opt,
+            corpus,
+            solutions,
+            saved,
+            Some(client),
+        );
+        // otherwise the client is restarted, whether it finished or failed
+        mgr.send_exiting()?;
+        if client == 0 {
+            let res = res?;
+            shmem.as_mut_slice()[0] = res.outcome as u8;
+            report = Some(res);
+        } else {
+            res?;
+        }
+        Ok(())
+    };
+
+    // the clients rebuild their state from their corpora when restarted, so it isn't kept
+    let res = Launcher::builder()
+        .shmem_provider(StdShMemProvider::new()?)
+        .configuration(EventConfig::from_name("sokoban"))
+        .monitor(monitor(opt.json))
+        .run_client(run_client)
+        .cores(cores)
+        .broker_port(opt.broker_port)
+        .serialize_state(false)
+        .build()
+        .launch();
+    // the restarter of a client which has exited ends up here too
+    match res {
+        Ok(()) | Err(Error::ShuttingDown) => {}
+        Err(e) => return Err(e),
+    }
+    if let Some(report) = report {
+        let outcome = report.outcome;
+        return Ok((Some(report), outcome));
+    }
+    let outcome = Outcome::from_u8(shmem.as_slice()[0]).unwrap();
+    Ok((None, outcome))
+}
+
+type Stat = (String, UserStatsValue, AggregatorOps);
+
+// corpus entries are counted in buckets of this many moves
+const DEPTH_BUCKET: usize = 50;
+
+// buckets that have emptied out are still reported, so the monitor doesn't keep a stale count
+fn campaign_stats<S>(state: &S, depth_buckets: &mut usize) -> Result<Vec<Stat>, Error>
+where
+    S: HasCorpus<Input = SokobanInput> + HasMetadata,
+{
+    let mut stats = Vec::new();
+    if let Ok(metadata) = state.metadata::<MutatorSuccessMetadata>() {
+        for mutator in metadata.stats() {
+            stats.push((
+                format!("success_{}", mutator.name()),
+                UserStatsValue::Ratio(mutator.added(), mutator.selected()),
+                AggregatorOps::Avg,
+            ));
+            stats.push((
+                format!("progress_{}", mutator.name()),
+                UserStatsValue::Ratio(mutator.progressed(), mutator.selected()),
+                AggregatorOps::Avg,
+            ));
+        }
+    }
+
+    if let Ok(campaign) = state.metadata::<CampaignStatsMetadata>() {
+        stats.push((
+            "pushes_per_exec".to_string(),
+            UserStatsValue::Float(campaign.pushes() as f64 / campaign.executions().max(1) as f64),
+            AggregatorOps::Avg,
+        ));
+        stats.push((
+            "configurations".to_string(),
+            UserStatsValue::Number(campaign.configurations() as u64),
+            AggregatorOps::Sum,
+        ));
+        for (rule, &count) in campaign.deadlocks() {
+            stats.push((
+                format!("deadlock_{rule}"),
+                UserStatsValue::Number(count),
+                AggregatorOps::Sum,
+            ));
+        }
+        for (mutator, &count) in campaign.skipped() {
+            stats.push((
+                format!("skipped_{mutator}"),
+                UserStatsValue::Number(count),
+                AggregatorOps::Sum,
+            ));
+        }
+        stats.push((
+            "push_to_failures".to_string(),
+            UserStatsValue::Number(campaign.push_to_failures()),
+            AggregatorOps::Sum,
+        ));
+        let (hits, lookups) = campaign.prefix_hits();
+        stats.push((
+            "prefix_cache_hits".to_string(),
+            UserStatsValue::Ratio(hits, lookups),
+            AggregatorOps::Avg,
+        ));
+        let (saved, moves) = campaign.prefix_moves_saved();
+        stats.push((
+            "prefix_cache_moves_saved".to_string(),
+            UserStatsValue::Ratio(saved, moves),
+            AggregatorOps::Avg,
+        ));
+    }
 
+    let mut buckets = vec![0; *depth_buckets];
+    for id in state.corpus().ids() {
+        let testcase = state.corpus().get(id)?.borrow();
+        let bucket = testcase.input().as_ref().unwrap().moves().len() / DEPTH_BUCKET;
+        if bucket >= buckets.len() {
+            buckets.resize(bucket + 1, 0);
+        }
+        buckets[bucket] += 1;
+    }
+    *depth_buckets = buckets.len();
+    for (bucket, count) in buckets.into_iter().enumerate() {
+        stats.push((
+            format!(
+                "corpus_moves_{}-{}",
+                bucket * DEPTH_BUCKET,
+                (bucket + 1) * DEPTH_BUCKET - 1
+            ),
+            UserStatsValue::Number(count),
+            AggregatorOps::Sum,
+        ));
+    }
+
+    Ok(stats)
+}
+
+fn report_stats<EM, C, SC>(
+    state: &mut SokobanFuzzState<C, SC>,
+    mgr: &mut EM,
+    stats: Vec<Stat>,
+    csv: Option<&mut BufWriter<File>>,
+    elapsed: Duration,
+) -> Result<(), Error>
+where
+    EM: EventFirer<State = SokobanFuzzState<C, SC>>,
+    C: Corpus<Input = SokobanInput>,
+    SC: Corpus<Input = SokobanInput>,
+{
+    if let Some(csv) = csv {
+        for (name, value, _) in &stats {
+            let value = match value {
+                UserStatsValue::Number(n) => n.to_string(),
+                UserStatsValue::Float(f) | UserStatsValue::Percent(f) => f.to_string(),
+                UserStatsValue::Ratio(a, b) => (*a as f64 / (*b).max(1) as f64).to_string(),
+                UserStatsValue::String(s) => s.clone(),
+            };
+            writeln!(
+                csv,
+                "{:.3},{},{name},{value}",
+                elapsed.as_secs_f64(),
+                state.executions()
+            )?;
+        }
+        csv.flush()?;
+    }
+
+    for (name, value, aggregator) in stats {
+        mgr.fire(
+            state,
+            UpdateUserStats {
+                name,
+                value: UserStats::new(value, aggregator),
+                phantom: Default::default(),
+            },
+        )?;
+    }
+    Ok(())
+}
+
+// anything in here is a bug, so make some noise about it
+fn report_invalid_inputs<S>(state: &S) -> Result<(), Error>
+where
+    S: HasMetadata,
+{
+    let invalid = state.metadata::<InvalidInputsMetadata>()?.corpus();
+    if invalid.count() == 0 {
+        return Ok(());
+    }
+    eprintln!("{} inputs were invalid:", invalid.count());
+    for id in invalid.ids() {
+        let testcase = invalid.get(id)?.borrow();
+        let invalid = testcase.metadata::<InvalidMoveMetadata>()?;
+        let mutator = invalid.mutator().unwrap_or("no mutator");
+        match invalid.why() {
+            InvalidMove::Illegal { index, board } => eprintln!(
+                "  from {mutator}: move {index} ({:?}) is illegal on\n{board:?}",
+                testcase.input().as_ref().unwrap().moves()[*index],
+            ),
+            InvalidMove::Mismatch {
+                hallucinated: (hallucinated, hallucinated_pushes),
+                reached: (reached, pushes),
+            } => eprintln!(
+                "  from {mutator}: hallucinated {hallucinated_pushes} pushes to\n{hallucinated:?}\nbut the moves make {pushes} pushes to\n{reached:?}"
+            ),
+        }
+    }
+    Ok(())
+}
+
+// hands the solutions we haven't yet to the other clients, which stop searching once they have
+// one; solutions they sent us are passed on once too, as we can't tell them apart
+fn share_solutions<EM, C, SC>(
+    state: &mut SokobanFuzzState<C, SC>,
+    mgr: &mut EM,
+    shared: &mut HashSet<Vec<Direction>>,
+) -> Result<(), Error>
+where
+    EM: EventFirer<State = SokobanFuzzState<C, SC>>,
+    C: Corpus<Input = SokobanInput>,
+    SC: Corpus<Input = SokobanInput>,
+{
+    for id in state.solutions().ids().collect::<Vec<_>>() {
+        let input = state.solutions().cloned_input_for_id(id)?;
+        if !shared.insert(input.moves().to_vec()) {
+            continue;
+        }
+        // without observers, the others run it themselves and find it solves the level
+        mgr.fire(
+            state,
+            NewTestcase {
+                input,
+                observers_buf: None,
+                exit_kind: ExitKind::Ok,
+                corpus_size: state.corpus().count(),
+                client_config: mgr.configuration(),
+                time: current_time(),
+                executions: *state.executions(),
+                forward_id: None,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+// client is our index among the cores when running on several of them
+fn fuzz<EM, C, SC>(
+    mgr: &mut EM,
+    puzzle: SokobanState,
+    opt: &Opt,
+    corpus: C,
+    solutions: SC,
+    mut saved: SavedCampaign,
+    client: Option<usize>,
+) -> Result<CampaignReport, Error>
+where
+    EM: EventFirer<State = SokobanFuzzState<C, SC>>
+        + EventRestarter
+        + ProgressReporter
+        + EventProcessor<
+            SokobanExecutor<SokobanObservers, SokobanFuzzState<C, SC>>,
+            SokobanFuzzer<SokobanFuzzState<C, SC>>,
+        >,
+    C: Corpus<Input = SokobanInput> + HasTestcase + Debug,
+    SC: Corpus<Input = SokobanInput> + Debug,
+{
+    // only one client streams to the viewer
+    let mut viewer = (!opt.no_viewer && client.unwrap_or(0) == 0).then(|| {
+        connect("wss://39c3.addisoncrump.info/sokoban/play")
+            .unwrap()
+            .0
+    });
+    // a second signal kills us as usual, should stopping take too long
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, stop.clone())?;
+        signal_hook::flag::register(signal, stop.clone())?;
+    }
+
+    let checkpoint = opt.checkpoint.as_ref().map(|path| match client {
+        Some(client) => {
+            let mut path = path.clone().into_os_string();
+            path.push(format!(".{client}"));
+            PathBuf::from(path)
+        }
+        None => path.clone(),
+    });
+    let resumed = if opt.resume {
+        let path = checkpoint.as_deref().unwrap();
+        Some(load_checkpoint::<SokobanFuzzState<C, SC>, ReverseCheckpoint>(path)?)
+    } else {
+        None
+    };
+
+    let start = Instant::now();
+    let initial = InitialPuzzleMetadata::new(puzzle.clone());
+    let board = initial.board().clone();
+    let sokoban_obs = SokobanStateObserver::new("sokoban_state", board.clone(), true);
+    let trajectory_obs = SokobanTrajectoryObserver::new("sokoban_trajectory", board.clone());
+
+    // rule out dead states first, so that they never count as progress
     let mut feedback = feedback_and_fast!(
-        NewHashFeedback::new(&sokoban_obs),
         SokobanSolvableFeedback::new(&sokoban_obs),
+        SokobanRevisitFeedback::new(&trajectory_obs),
+        feedback_or!(
+            SokobanShorterPathFeedback::new(&sokoban_obs),
+            SokobanFilledTargetsFeedback::new(&sokoban_obs),
+            SokobanSubgoalFeedback::new(&sokoban_obs)
+        ),
         SokobanStatisticsFeedback::new(&sokoban_obs)
     );
-    let mut objective = SokobanSolvedFeedback::new(&sokoban_obs);
+    // time spent before the checkpoint we resumed from
+    let offset = resumed
+        .as_ref()
+        .map_or(Duration::ZERO, |resumed| resumed.elapsed);
+    let mut objective = feedback_and_fast!(
+        feedback_or_fast!(
+            SokobanSolvedFeedback::new(&sokoban_obs),
+            SokobanMeetsReverseFeedback::new(&sokoban_obs)
+        ),
+        SolutionCostFeedback::new(start, offset)
+    );
+
+    let trajectory_name = trajectory_obs.name().to_string();
+    let observers = tuple_list!(sokoban_obs, trajectory_obs);
+    // the revisits are found along the trajectories
+    let mut executor = SokobanExecutor::new(board, initial.compact().clone(), observers)
+        .with_trajectory(&trajectory_name);
+    if opt.verify_hallucinations {
+        executor = executor.with_verification();
+    }
 
-    let observers = tuple_list!(sokoban_obs);
-    let mut executor = SokobanExecutor::new(puzzle.clone(), observers);
+    let resuming = resumed.is_some();
+    let mut seed = match opt.seed {
+        Some(seed) => seed.wrapping_add(client.unwrap_or(0) as u64),
+        None => current_nanos(),
+    };
+    let mut resumed_reverse = None;
+    let mut state = if let Some(resumed) = resumed {
+        if resumed.state.metadata::<InitialPuzzleMetadata>()?.initial() != puzzle {
+            return Err(Error::illegal_argument(
+                "the checkpoint belongs to a different level",
+            ));
+        }
+        seed = resumed.seed;
+        resumed_reverse = resumed.reverse;
+        saved.restore_files(&resumed.files)?;
+        save_corpus_metadata(resumed.state.corpus())?;
+        save_corpus_metadata(resumed.state.solutions())?;
+        resumed.state
+    } else {
+        let mut state = StdState::new(
+            StdRand::with_seed(seed),
+            corpus,
+            solutions,
+            &mut feedback,
+            &mut objective,
+        )?;
 
-    let mut state = StdState::new(
-        StdRand::new(),
-        InMemoryCorpus::new(),
-        InMemoryCorpus::new(),
-        &mut feedback,
-        &mut objective,
-    )?;
+        state.add_metadata(initial);
+        state.add_metadata(PushScoresMetadata::default());
+        state.add_metadata(ReverseStatesMetadata::default());
+        state.add_metadata(StatePathsMetadata::default());
+        state.add_metadata(PrefixCacheMetadata::default());
+        state.add_metadata(FilledTargetsMetadata::default());
+        state.add_metadata(FavouredMetadata::default());
+        state.add_metadata(InvalidInputsMetadata::default());
+        state.add_metadata(CampaignStatsMetadata::default());
+        state.add_metadata(SubgoalsMetadata::new(opt.subgoals.clone()));
+        state
+    };
+    // set again on resume, so that a resumed run can be given a larger budget
+    state.add_metadata(BudgetMetadata::new(
+        opt.max_executions,
+        opt.max_time.map(Duration::from_secs),
+        opt.max_corpus,
+        opt.max_memory.map(|max| max << 20),
+        start,
+        offset,
+    ));
 
-    state.add_metadata(InitialPuzzleMetadata::new(puzzle.clone()));
-    state.add_metadata(LastHallucinationMetadata::default());
+    let mut reverse = if opt.reverse {
+        let reverse = match resumed_reverse {
+            Some(resumed) => ReverseCampaign::resume(&puzzle, resumed)?,
+            None => ReverseCampaign::new(&puzzle, seed)?,
+        };
+        if reverse.is_none() {
+            eprintln!(
+                "puzzle has differing numbers of crates and targets; not searching in reverse"
+            );
+        }
+        reverse
+    } else {
+        None
+    };
+    if let Some(reverse) = reverse.as_mut() {
+        reverse.sync(state.metadata_mut()?)?;
+    }
 
     let scheduler = SokobanWeightScheduler::new();
 
     let mut fuzzer = StdFuzzer::new(scheduler, feedback, objective);
 
-    let _ = fuzzer.evaluate_input(
-        &mut state,
-        &mut executor,
-        mgr,
-        SokobanInput::new(Vec::new()),
-    )?;
+    for (input, metadata) in std::mem::take(&mut saved.solutions) {
+        let mut testcase = Testcase::new(input);
+        *testcase.metadata_map_mut() = metadata;
+        state.solutions_mut().add(testcase)?;
+    }
+    // pass this to --seed to repeat the run
+    report!(opt.json, "seed: {seed}");
+    if resuming {
+        report!(
+            opt.json,
+            "resuming from the checkpoint with {} corpus entries",
+            state.corpus().count()
+        );
+    } else if saved.queue.is_empty() {
+        let _ = fuzzer.evaluate_input(
+            &mut state,
+            &mut executor,
+            mgr,
+            SokobanInput::new(Vec::new()),
+        )?;
+    } else {
+        report!(
+            opt.json,
+            "resuming with {} corpus entries",
+            saved.queue.len()
+        );
+    }
+    // everything that was kept before is added again, rebuilding our metadata as it goes
+    for (input, mut metadata) in std::mem::take(&mut saved.queue) {
+        let solutions = state.solutions().count();
+        let id = fuzzer.add_input(&mut state, &mut executor, mgr, input)?;
+        if state.solutions().count() > solutions {
+            continue;
+        }
+        // pick up where the mutators left off on this entry
+        if let Some(remaining) = metadata.remove::<SokobanRemainingMutationsMetadata>() {
+            let mut testcase = state.corpus().get(id)?.borrow_mut();
+            testcase.add_metadata(*remaining);
+            save_testcase_metadata(&testcase)?;
+        }
+    }
+    // adding the entries again doesn't count, so the budgets go on from where the last run stopped
+    if !resuming {
+        if let Some(executions) = saved.executions {
+            *state.executions_mut() = executions;
+        }
+    }
+    saved.finish_resume()?;
+
+    // every mutator is tracked so that its skips are counted under its name
+    let oneshot_stage = HallucinatingStage::new(TrackedMutator::new(OneShotMutator));
+    let move_stage = HallucinatingStage::new(TrackedMutator::new(MoveCrateMutator::new()));
+    let move_to_target_stage =
+        HallucinatingStage::new(TrackedMutator::new(MoveCrateToTargetMutator::new()));
+    let straight_push_stage =
+        HallucinatingStage::new(TrackedMutator::new(StraightPushMutator::new()));
+    let splice_stage = HallucinatingStage::new(TrackedMutator::new(SpliceMutator));
+    let truncate_stage =
+        HallucinatingStage::new(TrackedMutator::new(TruncateMutator::new(tuple_list!(
+            OneShotMutator,
+            MoveCrateMutator::new(),
+            MoveCrateToTargetMutator::new(),
+            StraightPushMutator::new()
+        ))));
 
-    let oneshot_stage = StdMutationalStage::transforming(OneShotMutator);
-    let move_stage = StdMutationalStage::transforming(MoveCrateMutator);
-    let move_to_target_stage = StdMutationalStage::transforming(MoveCrateToTargetMutator);
+    let adaptive_stage = HallucinatingStage::new(RandomPreferenceMutator::new(tuple_list!(
+        TrackedMutator::new(OneShotMutator),
+        TrackedMutator::new(MoveCrateMutator::new()),
+        TrackedMutator::new(MoveCrateToTargetMutator::new()),
+        TrackedMutator::new(StraightPushMutator::new()),
+        TrackedMutator::new(SpliceMutator),
+        TrackedMutator::new(TruncateMutator::new(tuple_list!(
+            OneShotMutator,
+            MoveCrateMutator::new(),
+            MoveCrateToTargetMutator::new(),
+            StraightPushMutator::new()
+        )))
+    )));
 
-    let mut stages = tuple_list!(oneshot_stage, move_stage, move_to_target_stage);
+    let adaptive = opt.adaptive;
+    let mut stages = tuple_list!(IfElseStage::new(
+        move |_, _, _, _, _| Ok(adaptive),
+        tuple_list!(adaptive_stage),
+        tuple_list!(
+            oneshot_stage,
+            move_stage,
+            move_to_target_stage,
+            straight_push_stage,
+            splice_stage,
+            truncate_stage
+        ),
+    ));
 
     mgr.fire(&mut state, Objective { objective_size: 0 })?;
 
+    let mut csv = opt
+        .stats_csv
+        .as_ref()
+        .map(|path| -> Result<_, Error> {
+            let mut csv = BufWriter::new(File::create(path)?);
+            writeln!(csv, "secs,executions,name,value")?;
+            Ok(csv)
+        })
+        .transpose()?;
+    let mut depth_buckets = 0;
+
+    let interval = Duration::from_secs(opt.checkpoint_interval);
+    let mut last_checkpoint = Instant::now();
     let mut last_executions = 0;
-    while state.solutions().is_empty() {
+    let mut exhausted = None;
+    while state.solutions().is_empty() && !stop.load(Ordering::Relaxed) {
+        // a budget used up before we even start, as on resume, gives up straight away
+        exhausted = exhausted_budget(&state);
+        if exhausted.is_some() {
+            break;
+        }
         let _ = match fuzzer.fuzz_one(&mut stages, &mut executor, &mut state, mgr) {
             Err(Error::KeyNotFound(s, _bt))
-                if !state.solutions().is_empty()
-                    && s.starts_with("Missing corpus entry; is the corpus empty?") =>
+                if s.starts_with("Missing corpus entry; is the corpus empty?") =>
             {
-                // we found a solution at the exact same time we cleared to zero corpus entries
+                // either we found a solution at the exact same time we cleared to zero corpus
+                // entries, or there is nothing left to try
                 break;
             }
             r => r?,
         };
+        if let Some(id) = *state.corpus().current() {
+            if let Ok(testcase) = state.corpus().get(id) {
+                save_testcase_metadata(&testcase.borrow())?;
+            }
+        }
+        if let Some(reverse) = reverse.as_mut() {
+            reverse.fuzz_one()?;
+            reverse.sync(state.metadata_mut()?)?;
+        }
+        if let Some(path) = checkpoint.as_deref() {
+            // a checkpoint taken as a budget runs out can be resumed with a larger one
+            if last_checkpoint.elapsed() >= interval
+                || stop.load(Ordering::Relaxed)
+                || exhausted_budget(&state).is_some()
+            {
+                let checkpoint = Checkpoint {
+                    state: &state,
+                    seed,
+                    reverse: reverse.as_ref().map(ReverseCampaign::checkpoint),
+                    elapsed: offset + start.elapsed(),
+                    files: corpus_files(state.corpus())?
+                        .into_iter()
+                        .chain(corpus_files(state.solutions())?)
+                        .collect(),
+                };
+                save_checkpoint(path, &checkpoint)?;
+                last_checkpoint = Instant::now();
+            }
+        }
+        let subgoals = state.metadata::<SubgoalsMetadata>()?;
+        if subgoals.reached() {
+            let (goal, subgoal) = subgoals.current().unwrap();
+            report!(opt.json, "reached sub-goal {}: {subgoal}", goal + 1);
+            advance_subgoal(&mut state)?;
+        }
         if *state.executions() > last_executions + 500 {
             last_executions = *state.executions();
-            let last_input = state
-                .corpus()
-                .get(state.corpus().last().unwrap())?
-                .borrow()
-                .input()
-                .clone()
+            saved.save_executions(last_executions)?;
+            let mut stats = campaign_stats(&state, &mut depth_buckets)?;
+            if let Some(reverse) = reverse.as_ref() {
+                stats.push((
+                    "reverse_states".to_string(),
+                    UserStatsValue::Number(reverse.found() as u64),
+                    AggregatorOps::Sum,
+                ));
+            }
+            mgr.report_progress(&mut state)?;
+            report_stats(
+                &mut state,
+                mgr,
+                stats,
+                csv.as_mut(),
+                offset + start.elapsed(),
+            )?;
+            if let Some(ws) = viewer.as_mut() {
+                let last_input = state
+                    .corpus()
+                    .get(state.corpus().last().unwrap())?
+                    .borrow()
+                    .input()
+                    .clone()
+                    .unwrap();
+                ws.send(Message::Text(Utf8Bytes::from(serde_json::to_string(
+                    &last_input.moves(),
+                )?)))
                 .unwrap();
-            ws.send(Message::Text(Utf8Bytes::from(serde_json::to_string(
-                &last_input.moves(),
-            )?)))
-            .unwrap();
+            }
         }
     }
 
-    let mut smallest_id = state.solutions().first().unwrap();
-    let mut testcase = state.solutions().testcase_mut(smallest_id)?;
-    let moves = testcase.load_input(state.solutions())?;
+    report_invalid_inputs(&state)?;
+    saved.save_executions(*state.executions())?;
+
+    if state.solutions().is_empty() {
+        let outcome = Outcome::unsolved(stop.load(Ordering::Relaxed), exhausted.is_some());
+        report!(
+            opt.json,
+            "{} after {} executions, {:.1}s",
+            match (outcome, exhausted) {
+                (Outcome::Interrupted, _) => "interrupted".to_string(),
+                (_, Some(budget)) => format!("ran out of the {budget} budget"),
+                _ => "ran out of corpus entries".to_string(),
+            },
+            state.executions(),
+            (offset + start.elapsed()).as_secs_f64()
+        );
+        let best = best_partial(&mut state)?;
+        if let Some(best) = &best {
+            report!(
+                opt.json,
+                "best partial state ({}/{} targets filled, crate distance {}, {} moves): {:?}",
+                best.filled,
+                puzzle.targets().len(),
+                best.distance,
+                best.moves.len(),
+                best.moves
+            );
+            report!(opt.json, "{:?}", best.reached);
+            if let Some(output) = &opt.output {
+                let name = match client {
+                    Some(client) => format!("best-{client}.json"),
+                    None => "best.json".to_string(),
+                };
+                let entry = PartialEntry {
+                    filled: best.filled,
+                    targets: puzzle.targets().len(),
+                    distance: best.distance,
+                    moves: &best.moves,
+                };
+                serde_json::to_writer_pretty(File::create(output.join(name))?, &entry)?;
+            }
+        }
+        report!(opt.json, "no solution found");
 
-    println!("first solution: {:?}", moves.moves());
-    /*    drop(testcase);
+        let stats = campaign_stats(&state, &mut depth_buckets)?;
+        for (name, value, _) in &stats {
+            report!(opt.json, "stat {name}: {value}");
+        }
+        mgr.report_progress(&mut state)?;
+        report_stats(
+            &mut state,
+            mgr,
+            stats,
+            csv.as_mut(),
+            offset + start.elapsed(),
+        )?;
+        if let Some(mut ws) = viewer {
+            let _ = ws.close(None);
+            let _ = ws.flush();
+        }
+        return Ok(CampaignReport {
+            outcome,
+            solutions: Vec::new(),
+            executions: *state.executions(),
+            seed,
+            best_partial: best,
+        });
+    }
 
-        let move_stage = StdMutationalStage::transforming(MoveCrateMutator);
-        let move_to_target_stage = StdMutationalStage::transforming(MoveCrateToTargetMutator);
+    let first = state
+        .solutions()
+        .cloned_input_for_id(state.solutions().first().unwrap())?;
+    report!(opt.json, "first solution: {:?}", first.moves());
+    update_solution_front(&mut state, offset + start.elapsed(), opt.pareto_time)?;
+    save_corpus_metadata(state.solutions())?;
+    let mut shared = HashSet::new();
+    if client.is_some() {
+        share_solutions(&mut state, mgr, &mut shared)?;
+    }
 
+    // the first solution may already have been evicted from the front
+    let settled = settled_solution(&state)?.unwrap();
+    let mut moves = state.solutions().cloned_input_for_id(settled)?;
+    if let Some(metric) = opt.minimize {
         // oneshot is no longer worthwhile, as it poisons our minimisation
-        let mut stages = tuple_list!(move_stage, move_to_target_stage);
+        let mut stages = tuple_list!(
+            HallucinatingStage::new(TrackedMutator::new(MoveCrateMutator::new())),
+            HallucinatingStage::new(TrackedMutator::new(MoveCrateToTargetMutator::new())),
+            HallucinatingStage::new(TrackedMutator::new(StraightPushMutator::new())),
+            HallucinatingStage::new(TrackedMutator::new(TruncateMutator::new(tuple_list!(
+                MoveCrateMutator::new(),
+                MoveCrateToTargetMutator::new(),
+                StraightPushMutator::new()
+            ))))
+        );
 
+        let budget = *state.executions() + opt.minimize_budget;
+        let mut best_cost;
         loop {
-            let mut smallest_len = usize::MAX;
-            for id in state.solutions().ids() {
-                let mut testcase = state.solutions().testcase_mut(id)?;
-                let input = testcase.load_input(state.solutions())?;
-                if input.moves().len() < smallest_len {
-                    smallest_len = input.moves().len();
-                    smallest_id = id;
-                }
+            update_solution_front(&mut state, offset + start.elapsed(), opt.pareto_time)?;
+            save_corpus_metadata(state.solutions())?;
+            if client.is_some() {
+                share_solutions(&mut state, mgr, &mut shared)?;
             }
+            let best = state
+                .solutions()
+                .ids()
+                .min_by_key(|&id| {
+                    let testcase = state.solutions().get(id).unwrap().borrow();
+                    metric.cost(testcase.metadata::<SolutionCostMetadata>().unwrap())
+                })
+                .unwrap();
+            moves = state.solutions().cloned_input_for_id(best)?;
+            best_cost = *state
+                .solutions()
+                .get(best)?
+                .borrow()
+                .metadata::<SolutionCostMetadata>()?;
 
-            state.set_max_size(smallest_len);
+            // nothing beats a level that is solved from the start
+            if moves.moves().is_empty() {
+                break;
+            }
+            // only a shorter solution can have fewer moves; fewer pushes may take more walking
+            if metric == Metric::Moves {
+                state.set_max_size(best_cost.moves() - 1);
+            }
 
-            if state.corpus().is_empty() {
+            // the search budgets cut minimizing short too, keeping what we have
+            if state.corpus().is_empty()
+                || *state.executions() >= budget
+                || stop.load(Ordering::Relaxed)
+                || exhausted_budget(&state).is_some()
+            {
                 break;
             }
 
@@ -263,18 +1391,69 @@ fn fuzz(mgr: &mut SokobanManager<impl Monitor>, puzzle: SokobanState) -> Result<
                 Err(Error::KeyNotFound(s, _bt))
                     if s.starts_with("Missing corpus entry; is the corpus empty?") =>
                 {
-                    // we found a solution at the exact same time we cleared to zero corpus entries
+                    // the last corpus entry was exhausted; the loop checks for emptiness
                     continue;
                 }
                 r => r?,
             };
         }
 
-        let mut testcase = state.solutions().testcase_mut(smallest_id)?;
-        let moves = testcase.load_input(state.solutions())?;
+        report!(
+            opt.json,
+            "best solution ({} moves, {} pushes): {:?}",
+            best_cost.moves(),
+            best_cost.pushes(),
+            moves.moves()
+        );
+    }
+
+    let rerouted = reroute_walks(&puzzle, moves.moves());
+    if rerouted.len() < moves.moves().len() {
+        report!(
+            opt.json,
+            "rerouted solution ({} moves): {:?}",
+            rerouted.len(),
+            rerouted
+        );
+        // same pushes in fewer moves, so it takes the place of the solution it came from
+        moves = SokobanInput::new(rerouted);
+        state.solutions_mut().add(Testcase::new(moves.clone()))?;
+        update_solution_front(&mut state, offset + start.elapsed(), opt.pareto_time)?;
+        save_corpus_metadata(state.solutions())?;
+        if client.is_some() {
+            share_solutions(&mut state, mgr, &mut shared)?;
+        }
+    }
+
+    let mut front = Vec::new();
+    for id in state.solutions().ids() {
+        let testcase = state.solutions().get(id)?.borrow();
+        let cost = *testcase.metadata::<SolutionCostMetadata>()?;
+        front.push((cost, testcase.input().clone().unwrap()));
+    }
+    front.sort_by_key(|(cost, _)| (cost.moves(), cost.pushes()));
+    for (cost, _) in &front {
+        report!(
+            opt.json,
+            "front: {} moves, {} pushes, found after {:.1}s",
+            cost.moves(),
+            cost.pushes(),
+            cost.found().as_secs_f64()
+        );
+    }
+    if let Some(path) = &opt.front {
+        let entries = front
+            .iter()
+            .map(|(cost, solution)| FrontEntry {
+                moves: cost.moves(),
+                pushes: cost.pushes(),
+                found_secs: cost.found().as_secs_f64(),
+                solution: solution.moves(),
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_writer_pretty(File::create(path)?, &entries)?;
+    }
 
-        println!("minimised: {:?}", moves.moves());
-    */
     let solution = moves
         .moves()
         .iter()
@@ -283,22 +1462,90 @@ fn fuzz(mgr: &mut SokobanManager<impl Monitor>, puzzle: SokobanState) -> Result<
             puzzle.move_player(direction)
         })
         .unwrap();
-    ws.send(Message::Text(Utf8Bytes::from(serde_json::to_string(
-        &moves.moves(),
-    )?)))
-    .unwrap();
 
-    println!("solved: {solution:?}");
-    std::thread::sleep(Duration::from_secs(5));
+    report!(opt.json, "solved: {solution:?}");
+
+    if let Some(path) = &opt.export_trajectory {
+        let (steps, _) = trajectory(&puzzle, moves.moves()).unwrap();
+        let steps = steps
+            .iter()
+            .map(|step| TrajectoryStep {
+                player: step.player(),
+                crates: find_crates(step),
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_writer_pretty(File::create(path)?, &steps)?;
+    }
 
-    for i in 0..=moves.moves().len() {
+    let stopped = stop.load(Ordering::Relaxed);
+    if let Some(mut ws) = viewer {
         ws.send(Message::Text(Utf8Bytes::from(serde_json::to_string(
-            &moves.moves()[..i],
+            &moves.moves(),
         )?)))
         .unwrap();
-        std::thread::sleep(Duration::from_millis(250));
+
+        // no replay when we've been asked to stop
+        if !stopped {
+            std::thread::sleep(Duration::from_secs(5));
+            for i in 0..=moves.moves().len() {
+                ws.send(Message::Text(Utf8Bytes::from(serde_json::to_string(
+                    &moves.moves()[..i],
+                )?)))
+                .unwrap();
+                std::thread::sleep(Duration::from_millis(250));
+            }
+            std::thread::sleep(Duration::from_secs(5));
+        }
+        let _ = ws.close(None);
+        let _ = ws.flush();
     }
-    std::thread::sleep(Duration::from_secs(5));
 
-    Ok(())
+    // what we settled on, then the rest of the front
+    let settled = moves.moves().to_vec();
+    let mut solutions = front
+        .into_iter()
+        .map(|(_, solution)| solution.moves().to_vec())
+        .filter(|solution| solution != &settled)
+        .collect::<Vec<_>>();
+    solutions.insert(0, settled);
+    saved.save_executions(*state.executions())?;
+    Ok(CampaignReport {
+        outcome: if stopped {
+            Outcome::Interrupted
+        } else {
+            Outcome::Solved
+        },
+        solutions,
+        executions: *state.executions(),
+        seed,
+        best_partial: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Outcome;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_outcome_exit_codes() {
+        let codes = Outcome::ALL.map(Outcome::exit_code);
+        assert_eq!(codes, [0, 3, 130, 124, 1]);
+        assert_eq!(codes.iter().collect::<HashSet<_>>().len(), codes.len());
+
+        // stopping wins over running out of budget, which wins over running out of entries
+        assert_eq!(Outcome::unsolved(true, true), Outcome::Interrupted);
+        assert_eq!(Outcome::unsolved(false, true), Outcome::Timeout);
+        assert_eq!(Outcome::unsolved(false, false), Outcome::Unsolvable);
+
+        // the broker gets the same outcome back, and --json names it
+        for outcome in Outcome::ALL {
+            assert_eq!(Outcome::from_u8(outcome as u8), Some(outcome));
+        }
+        assert_eq!(Outcome::from_u8(Outcome::ALL.len() as u8), None);
+        assert_eq!(
+            serde_json::to_string(&Outcome::ALL).unwrap(),
+            r#"["solved","unsolvable","interrupted","timeout","error"]"#
+        );
+    }
 }
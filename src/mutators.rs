@@ -1,7 +1,9 @@
-use crate::input::HallucinatedSokobanInput;
+use crate::board::{CompactState, StaticBoard};
+use crate::input::{HallucinatedSokobanInput, ReverseSokobanInput, SokobanInput};
+use crate::state::{replay_cached, CampaignStatsMetadata, InitialPuzzleMetadata};
 use crate::util;
-use crate::util::{find_crates, opposite, push_to, POSSIBLE_MOVES};
-use libafl::corpus::{Corpus, HasTestcase};
+use crate::util::{find_crates, opposite, POSSIBLE_MOVES};
+use libafl::corpus::{Corpus, CorpusId, HasTestcase};
 use libafl::mutators::{MutationResult, Mutator, MutatorsTuple};
 use libafl::prelude::MutationId;
 use libafl::state::{HasCorpus, HasMaxSize, HasMetadata, HasRand};
@@ -12,22 +14,47 @@ use rand::seq::SliceRandom;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sokoban::{Direction, Tile};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+type MoveCandidate = ((usize, usize), Direction);
+type MoveToTargetCandidate = ((usize, usize), (usize, usize));
+type StraightPushCandidate = ((usize, usize), Direction, usize);
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SokobanRemainingMutationsMetadata {
-    moves_remaining: Vec<((usize, usize), Direction)>,
-    move_to_targets_remaining: Vec<((usize, usize), (usize, usize))>,
+    moves_remaining: Vec<MoveCandidate>,
+    move_to_targets_remaining: Vec<MoveToTargetCandidate>,
+    straight_pushes_remaining: Vec<StraightPushCandidate>,
 }
 
 impl_serdeany!(SokobanRemainingMutationsMetadata);
 
 impl SokobanRemainingMutationsMetadata {
-    pub fn new(crates: &[(usize, usize)], targets: &[(usize, usize)]) -> Self {
+    pub fn new(board: &StaticBoard, state: &CompactState) -> Self {
+        let crates = state.crate_positions(board);
+        let targets = board.targets();
         let mut moves_remaining = Vec::with_capacity(crates.len() * 4);
         let mut move_to_targets_remaining = Vec::with_capacity(crates.len() * targets.len());
-        for &moved in crates {
+        let mut straight_pushes_remaining = Vec::new();
+        for &moved in &crates {
             for direction in POSSIBLE_MOVES {
                 moves_remaining.push((moved, direction));
+
+                // every stopping point before the first obstacle or dead square; pushing a single
+                // square is already covered by the move above
+                let mut position = moved;
+                let mut distance = 0;
+                while let Some(next) = direction
+                    .go(position)
+                    .filter(|&next| state.is_floor(board, next) && !board.is_dead_square(next))
+                {
+                    position = next;
+                    distance += 1;
+                    if distance > 1 {
+                        straight_pushes_remaining.push((moved, direction, distance));
+                    }
+                }
             }
             for &target in targets {
                 move_to_targets_remaining.push((moved, target));
@@ -36,15 +63,135 @@ impl SokobanRemainingMutationsMetadata {
         Self {
             moves_remaining,
             move_to_targets_remaining,
+            straight_pushes_remaining,
         }
     }
 
+    pub fn clear(&mut self) {
+        self.moves_remaining.clear();
+        self.move_to_targets_remaining.clear();
+        self.straight_pushes_remaining.clear();
+    }
+
     pub fn remaining(&self) -> usize {
-        self.moves_remaining.len() + self.move_to_targets_remaining.len()
+        self.moves_remaining.len()
+            + self.move_to_targets_remaining.len()
+            + self.straight_pushes_remaining.len()
+    }
+
+    fn pop_move(&mut self, scores: &PushScoresMetadata) -> Option<MoveCandidate> {
+        let idx = scores.moves.best(&self.moves_remaining)?;
+        Some(self.moves_remaining.swap_remove(idx))
+    }
+
+    fn pop_move_to_target(&mut self, scores: &PushScoresMetadata) -> Option<MoveToTargetCandidate> {
+        let idx = scores
+            .move_to_targets
+            .best(&self.move_to_targets_remaining)?;
+        Some(self.move_to_targets_remaining.swap_remove(idx))
+    }
+
+    fn pop_straight_push(&mut self, scores: &PushScoresMetadata) -> Option<StraightPushCandidate> {
+        let idx = scores
+            .straight_pushes
+            .best(&self.straight_pushes_remaining)?;
+        Some(self.straight_pushes_remaining.swap_remove(idx))
+    }
+}
+
+const UCB_EXPLORATION: f64 = std::f64::consts::SQRT_2;
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct CandidateScore {
+    tries: u64,
+    reward: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CandidateScores<K>
+where
+    K: Eq + Hash,
+{
+    scores: HashMap<K, CandidateScore>,
+    total: u64,
+}
+
+impl<K> Default for CandidateScores<K>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self {
+            scores: HashMap::new(),
+            total: 0,
+        }
     }
 }
 
-pub struct MoveCrateMutator;
+impl<K> CandidateScores<K>
+where
+    K: Eq + Hash,
+{
+    fn ucb(&self, key: &K) -> f64 {
+        match self.scores.get(key) {
+            Some(score) if score.tries > 0 => {
+                score.reward / score.tries as f64
+                    + UCB_EXPLORATION * ((self.total as f64).ln() / score.tries as f64).sqrt()
+            }
+            _ => f64::INFINITY,
+        }
+    }
+
+    // ties go to the later candidate, so untried candidates keep construction order
+    fn best(&self, candidates: &[K]) -> Option<usize> {
+        let mut best = None;
+        let mut best_score = f64::NEG_INFINITY;
+        for (i, candidate) in candidates.iter().enumerate() {
+            let score = self.ucb(candidate);
+            if score >= best_score {
+                best = Some(i);
+                best_score = score;
+            }
+        }
+        best
+    }
+
+    fn reward(&mut self, key: K, reward: f64) {
+        let score = self.scores.entry(key).or_default();
+        score.tries += 1;
+        score.reward += reward;
+        self.total += 1;
+    }
+}
+
+// learned scores for push candidates by crate position, shared across corpus entries
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PushScoresMetadata {
+    moves: CandidateScores<MoveCandidate>,
+    move_to_targets: CandidateScores<MoveToTargetCandidate>,
+    straight_pushes: CandidateScores<StraightPushCandidate>,
+}
+
+impl_serdeany!(PushScoresMetadata);
+
+fn push_reward(corpus_idx: Option<CorpusId>, progressed: bool) -> f64 {
+    match (corpus_idx, progressed) {
+        (None, _) => 0.0,
+        (Some(_), false) => 1.0,
+        (Some(_), true) => 1.0 + PROGRESS_BONUS,
+    }
+}
+
+#[derive(Default)]
+pub struct MoveCrateMutator {
+    last: Option<(MoveCandidate, bool)>,
+}
+
+impl MoveCrateMutator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
 
 impl Named for MoveCrateMutator {
     fn name(&self) -> &str {
@@ -72,40 +219,32 @@ where
             return Ok(MutationResult::Skipped);
         }
 
-        let current = input.hallucinated_mut().take().unwrap();
+        let board = state.metadata::<InitialPuzzleMetadata>()?.board().clone();
+        let current = input.hallucinated().clone();
 
         loop {
             // get the available mutations
             let mut testcase = state.testcase_mut(idx)?;
             let remaining = testcase.metadata_mut::<SokobanRemainingMutationsMetadata>()?;
 
-            if remaining.moves_remaining.is_empty() {
-                input.hallucinated_mut().replace(current);
+            let Some((target, direction)) =
+                remaining.pop_move(state.metadata::<PushScoresMetadata>()?)
+            else {
                 return Ok(MutationResult::Skipped);
-            }
-            let (target, direction) = remaining.moves_remaining.pop().unwrap();
+            };
 
             if let Some(potential) = direction.go(target) {
-                if current[potential] == Tile::Floor {
+                if current.is_floor(&board, potential) {
                     if let Some(destination) = opposite(direction).go(target) {
-                        if let Some(moves) = util::go_to(current.player(), destination, &current) {
+                        if let Some(moves) = current.go_to(&board, destination) {
                             if moves.len() + input.moves().len() > state.max_size() {
-                                input.hallucinated_mut().replace(current);
                                 return Ok(MutationResult::Skipped);
                             }
 
-                            input.hallucinated_mut().replace(
-                                moves
-                                    .iter()
-                                    .copied()
-                                    .try_fold(current, |current, direction| {
-                                        current.move_player(direction)
-                                    })
-                                    .and_then(|current| current.move_player(direction))
-                                    .unwrap(),
-                            );
-                            input.moves_mut().extend(moves);
-                            input.moves_mut().push(direction);
+                            let filled = current.filled(&board);
+                            input.play(&board, moves.into_iter().chain([direction]));
+                            let progressed = input.hallucinated().filled(&board) > filled;
+                            self.last = Some(((target, direction), progressed));
                             return Ok(MutationResult::Mutated);
                         }
                     }
@@ -113,9 +252,33 @@ where
             }
         }
     }
+
+    fn post_exec(
+        &mut self,
+        state: &mut S,
+        _stage_idx: i32,
+        corpus_idx: Option<CorpusId>,
+    ) -> Result<(), Error> {
+        if let Some((key, progressed)) = self.last.take() {
+            state
+                .metadata_mut::<PushScoresMetadata>()?
+                .moves
+                .reward(key, push_reward(corpus_idx, progressed));
+        }
+        Ok(())
+    }
 }
 
-pub struct MoveCrateToTargetMutator;
+#[derive(Default)]
+pub struct MoveCrateToTargetMutator {
+    last: Option<(MoveToTargetCandidate, bool)>,
+}
+
+impl MoveCrateToTargetMutator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
 
 impl Named for MoveCrateToTargetMutator {
     fn name(&self) -> &str {
@@ -143,36 +306,433 @@ where
             return Ok(MutationResult::Skipped);
         }
 
-        let current = input.hallucinated_mut().take().unwrap();
+        let board = state.metadata::<InitialPuzzleMetadata>()?.board().clone();
+        let current = input.hallucinated().clone();
 
         loop {
             // get the available mutations
             let mut testcase = state.testcase_mut(idx)?;
             let remaining = testcase.metadata_mut::<SokobanRemainingMutationsMetadata>()?;
 
-            if remaining.move_to_targets_remaining.is_empty() {
-                input.hallucinated_mut().replace(current);
+            let Some((moved, target)) =
+                remaining.pop_move_to_target(state.metadata::<PushScoresMetadata>()?)
+            else {
                 return Ok(MutationResult::Skipped);
-            }
-            let (moved, target) = remaining.move_to_targets_remaining.pop().unwrap();
+            };
+            drop(testcase);
 
-            if let Some(moves) = push_to(moved, target, &current) {
+            if let Some(moves) = current.push_to(&board, moved, target) {
                 if moves.len() + input.moves().len() > state.max_size() {
-                    input.hallucinated_mut().replace(current);
                     return Ok(MutationResult::Skipped);
                 }
 
-                input.hallucinated_mut().replace(
-                    moves
-                        .iter()
-                        .copied()
-                        .try_fold(current, |current, direction| current.move_player(direction))
-                        .unwrap(),
-                );
-                input.moves_mut().extend(moves);
+                let filled = current.filled(&board);
+                input.play(&board, moves);
+                let progressed = input.hallucinated().filled(&board) > filled;
+                self.last = Some(((moved, target), progressed));
+                return Ok(MutationResult::Mutated);
+            } else if let Ok(stats) = state.metadata_mut::<CampaignStatsMetadata>() {
+                stats.record_push_to_failure();
+            }
+        }
+    }
+
+    fn post_exec(
+        &mut self,
+        state: &mut S,
+        _stage_idx: i32,
+        corpus_idx: Option<CorpusId>,
+    ) -> Result<(), Error> {
+        if let Some((key, progressed)) = self.last.take() {
+            state
+                .metadata_mut::<PushScoresMetadata>()?
+                .move_to_targets
+                .reward(key, push_reward(corpus_idx, progressed));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct StraightPushMutator {
+    last: Option<(StraightPushCandidate, bool)>,
+}
+
+impl StraightPushMutator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Named for StraightPushMutator {
+    fn name(&self) -> &str {
+        "push_crate_straight"
+    }
+}
+
+impl<S> Mutator<HallucinatedSokobanInput, S> for StraightPushMutator
+where
+    S: HasCorpus + HasMaxSize + HasMetadata + HasRand + HasTestcase,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut HallucinatedSokobanInput,
+        _stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        let idx = state.corpus().current().unwrap();
+
+        if state.max_size() <= input.moves().len() {
+            let mut testcase = state.testcase_mut(idx)?;
+            let remaining = testcase.metadata_mut::<SokobanRemainingMutationsMetadata>()?;
+            remaining.straight_pushes_remaining.clear();
+            return Ok(MutationResult::Skipped);
+        }
+
+        let board = state.metadata::<InitialPuzzleMetadata>()?.board().clone();
+        let current = input.hallucinated().clone();
+
+        loop {
+            // get the available mutations
+            let mut testcase = state.testcase_mut(idx)?;
+            let remaining = testcase.metadata_mut::<SokobanRemainingMutationsMetadata>()?;
+
+            let Some((moved, direction, distance)) =
+                remaining.pop_straight_push(state.metadata::<PushScoresMetadata>()?)
+            else {
+                return Ok(MutationResult::Skipped);
+            };
+
+            let mut path = std::iter::successors(Some(moved), |&position| direction.go(position))
+                .skip(1)
+                .take(distance);
+            if !path.all(|next| current.is_floor(&board, next)) {
+                continue;
+            }
+
+            if let Some(destination) = opposite(direction).go(moved) {
+                if let Some(moves) = current.go_to(&board, destination) {
+                    if moves.len() + distance + input.moves().len() > state.max_size() {
+                        return Ok(MutationResult::Skipped);
+                    }
+
+                    let filled = current.filled(&board);
+                    input.play(
+                        &board,
+                        moves
+                            .into_iter()
+                            .chain(std::iter::repeat_n(direction, distance)),
+                    );
+                    let progressed = input.hallucinated().filled(&board) > filled;
+                    self.last = Some(((moved, direction, distance), progressed));
+                    return Ok(MutationResult::Mutated);
+                }
+            }
+        }
+    }
+
+    fn post_exec(
+        &mut self,
+        state: &mut S,
+        _stage_idx: i32,
+        corpus_idx: Option<CorpusId>,
+    ) -> Result<(), Error> {
+        if let Some((key, progressed)) = self.last.take() {
+            state
+                .metadata_mut::<PushScoresMetadata>()?
+                .straight_pushes
+                .reward(key, push_reward(corpus_idx, progressed));
+        }
+        Ok(())
+    }
+}
+
+pub struct TruncateMutator<MT> {
+    mutators: MT,
+    last: Option<usize>,
+}
+
+impl<MT> TruncateMutator<MT> {
+    pub fn new(mutators: MT) -> Self {
+        Self {
+            mutators,
+            last: None,
+        }
+    }
+}
+
+impl<MT> Named for TruncateMutator<MT> {
+    fn name(&self) -> &str {
+        "truncate_to_push"
+    }
+}
+
+impl<MT, S> Mutator<HallucinatedSokobanInput, S> for TruncateMutator<MT>
+where
+    MT: MutatorsTuple<HallucinatedSokobanInput, S>,
+    S: HasCorpus + HasMetadata + HasRand + HasTestcase,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut HallucinatedSokobanInput,
+        stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        self.last = None;
+
+        let initial = state.metadata::<InitialPuzzleMetadata>()?;
+        let board = initial.board().clone();
+        let boundaries = board
+            .push_boundaries(initial.compact(), input.moves())
+            .expect("Invalid sequence of moves while truncating!");
+
+        // truncating to the last boundary would only drop trailing walks
+        if boundaries.len() < 2 {
+            return Ok(MutationResult::Skipped);
+        }
+        // the prefix up to boundary i makes i pushes
+        let pushes = state.rand_mut().below(boundaries.len() as u64 - 1) as usize;
+        let keep = boundaries[pushes];
+
+        let (truncated, _) = replay_cached(state, &input.moves()[..keep])?.unwrap();
+
+        // the other mutators draw their candidates from the current testcase, so hand them
+        // candidates for the truncated state and restore the originals afterwards
+        let idx = state.corpus().current().unwrap();
+        let truncated_remaining = SokobanRemainingMutationsMetadata::new(&board, &truncated);
+        input.truncate(keep, truncated, pushes);
+
+        let original = state
+            .testcase_mut(idx)?
+            .metadata_map_mut()
+            .remove::<SokobanRemainingMutationsMetadata>();
+        state.testcase_mut(idx)?.add_metadata(truncated_remaining);
+
+        let mutator = state.rand_mut().below(self.mutators.len() as u64) as usize;
+        let result =
+            self.mutators
+                .get_and_mutate(MutationId::from(mutator), state, input, stage_idx);
+
+        let mut testcase = state.testcase_mut(idx)?;
+        if let Some(original) = original {
+            testcase.add_metadata(*original);
+        } else {
+            let _ = testcase
+                .metadata_map_mut()
+                .remove::<SokobanRemainingMutationsMetadata>();
+        }
+        drop(testcase);
+
+        if result.as_ref().is_ok_and(|&r| r == MutationResult::Mutated) {
+            self.last = Some(mutator);
+        }
+        result
+    }
+
+    fn post_exec(
+        &mut self,
+        state: &mut S,
+        stage_idx: i32,
+        corpus_idx: Option<CorpusId>,
+    ) -> Result<(), Error> {
+        if let Some(mutator) = self.last.take() {
+            self.mutators
+                .get_and_post_exec(mutator, state, stage_idx, corpus_idx)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct SpliceMutator;
+
+impl Named for SpliceMutator {
+    fn name(&self) -> &str {
+        "splice_pushes"
+    }
+}
+
+impl<S> Mutator<HallucinatedSokobanInput, S> for SpliceMutator
+where
+    S: HasCorpus<Input = SokobanInput> + HasMaxSize + HasMetadata + HasRand,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut HallucinatedSokobanInput,
+        _stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        if state.max_size() <= input.moves().len() {
+            return Ok(MutationResult::Skipped);
+        }
+
+        let count = state.corpus().count();
+        if count < 2 {
+            return Ok(MutationResult::Skipped);
+        }
+        let other = state.rand_mut().below(count as u64) as usize;
+        let other = state.corpus().nth(other);
+        if Some(other) == *state.corpus().current() {
+            return Ok(MutationResult::Skipped);
+        }
+        let other = state.corpus().cloned_input_for_id(other)?;
+
+        let initial = state.metadata::<InitialPuzzleMetadata>()?;
+        let board = initial.board().clone();
+        let (other_pushes, _) = board
+            .crate_pushes(initial.compact(), other.moves())
+            .expect("Invalid sequence of moves while splicing!");
+        let (pushes, mut positions) = board
+            .crate_pushes(initial.compact(), input.moves())
+            .expect("Invalid sequence of moves while splicing!");
+
+        // pushes both entries share are already reflected in the current state
+        let shared = pushes
+            .iter()
+            .zip(&other_pushes)
+            .take_while(|(ours, theirs)| ours == theirs)
+            .count();
+
+        let mut mutated = MutationResult::Skipped;
+
+        for &(pushed, direction) in &other_pushes[shared..] {
+            let current = input.hallucinated();
+            let position = positions[pushed];
+            let Some(next) = direction.go(position) else {
+                continue;
+            };
+            if !current.is_floor(&board, next) {
+                continue;
+            }
+            let Some(push_point) = opposite(direction).go(position) else {
+                continue;
+            };
+            let Some(moves) = current.go_to(&board, push_point) else {
+                continue;
+            };
+            if moves.len() + 1 + input.moves().len() > state.max_size() {
+                break; // we may have already mutated the input
+            }
+
+            input.play(&board, moves.into_iter().chain([direction]));
+            positions[pushed] = next;
+            mutated = MutationResult::Mutated;
+        }
+
+        Ok(mutated)
+    }
+}
+
+pub struct PullCrateMutator;
+
+impl Named for PullCrateMutator {
+    fn name(&self) -> &str {
+        "pull_crate"
+    }
+}
+
+impl<S> Mutator<ReverseSokobanInput, S> for PullCrateMutator
+where
+    S: HasMaxSize + HasMetadata + HasRand,
+    S::Rand: RngCore,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut ReverseSokobanInput,
+        _stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        if state.max_size() <= input.moves().len() {
+            return Ok(MutationResult::Skipped);
+        }
+
+        let current = input
+            .replay(&state.metadata::<InitialPuzzleMetadata>()?.initial())
+            .expect("Invalid sequence of pulls while mutating!");
+
+        let mut candidates = find_crates(&current)
+            .into_iter()
+            .flat_map(|moved| POSSIBLE_MOVES.map(|direction| (moved, direction)))
+            .collect::<Vec<_>>();
+        candidates.shuffle(state.rand_mut());
+
+        for (moved, direction) in candidates {
+            // the player pulls from next to the crate and steps back onto the destination
+            let Some(pull_point) = direction.go(moved) else {
+                continue;
+            };
+            let Some(destination) = direction.go(pull_point) else {
+                continue;
+            };
+            if destination.0 >= current.rows()
+                || destination.1 >= current.cols()
+                || current[destination] != Tile::Floor
+            {
+                continue;
+            }
+            if let Some(moves) = util::go_to(current.player(), pull_point, &current) {
+                if moves.len() + 1 + input.moves().len() > state.max_size() {
+                    continue;
+                }
+
+                input
+                    .moves_mut()
+                    .extend(moves.into_iter().map(|direction| (direction, false)));
+                input.moves_mut().push((direction, true));
                 return Ok(MutationResult::Mutated);
             }
         }
+
+        Ok(MutationResult::Skipped)
+    }
+}
+
+// counts the times the mutator it wraps had nothing to do, under its name
+pub struct TrackedMutator<M> {
+    inner: M,
+}
+
+impl<M> TrackedMutator<M> {
+    pub fn new(inner: M) -> Self {
+        Self { inner }
+    }
+}
+
+impl<M> Named for TrackedMutator<M>
+where
+    M: Named,
+{
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+impl<I, M, S> Mutator<I, S> for TrackedMutator<M>
+where
+    M: Mutator<I, S> + Named,
+    S: HasMetadata,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut I,
+        stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        let result = self.inner.mutate(state, input, stage_idx)?;
+        if result == MutationResult::Skipped {
+            if let Ok(stats) = state.metadata_mut::<CampaignStatsMetadata>() {
+                stats.record_skip(self.inner.name());
+            }
+        }
+        Ok(result)
+    }
+
+    fn post_exec(
+        &mut self,
+        state: &mut S,
+        stage_idx: i32,
+        corpus_idx: Option<CorpusId>,
+    ) -> Result<(), Error> {
+        self.inner.post_exec(state, stage_idx, corpus_idx)
     }
 }
 
@@ -199,56 +759,99 @@ where
             return Ok(MutationResult::Skipped);
         }
 
-        let mut current = input.hallucinated_mut().take().unwrap();
-        if current.in_solution_state() {
-            input.hallucinated_mut().replace(current);
+        let board = state.metadata::<InitialPuzzleMetadata>()?.board().clone();
+        if input.hallucinated().in_solution_state(&board) {
             return Ok(MutationResult::Skipped);
         }
-
-        let mut targets = current.targets().to_vec();
+        let mut targets = board.targets().to_vec();
         targets.shuffle(state.rand_mut());
 
-        let mut crates = find_crates(&current);
+        let mut crates = input.hallucinated().crate_positions(&board);
         crates.shuffle(state.rand_mut());
 
         let mut mutated = MutationResult::Skipped;
 
         for (target, moved) in targets.into_iter().zip(crates) {
-            if let Some(moves) = push_to(moved, target, &current) {
+            if let Some(moves) = input.hallucinated().push_to(&board, moved, target) {
                 if moves.len() + input.moves().len() > state.max_size() {
                     break; // we may have already mutated the input
                 }
 
-                current = moves
-                    .iter()
-                    .copied()
-                    .try_fold(current, |puzzle, direction| puzzle.move_player(direction))
-                    .unwrap();
-                input.moves_mut().extend(moves);
+                input.play(&board, moves);
                 mutated = MutationResult::Mutated;
             } else {
+                if let Ok(stats) = state.metadata_mut::<CampaignStatsMetadata>() {
+                    stats.record_push_to_failure();
+                }
                 break;
             }
 
-            if current.in_solution_state() {
+            if input.hallucinated().in_solution_state(&board) {
                 break;
             }
         }
 
-        input.hallucinated_mut().replace(current);
-
         Ok(mutated)
     }
 }
 
 const WEIGHT_PRECISION: u64 = 64;
-const REWEIGHT_FREQUENCY: usize = 10_000;
+const REWEIGHT_FREQUENCY: usize = 1_000;
+// filling a new target is worth this many plain corpus additions
+const PROGRESS_BONUS: f64 = 4.0;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MutatorStats {
+    name: String,
+    selected: u64,
+    skipped: u64,
+    added: u64,
+    progressed: u64,
+}
+
+impl MutatorStats {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn selected(&self) -> u64 {
+        self.selected
+    }
+
+    pub fn added(&self) -> u64 {
+        self.added
+    }
+
+    pub fn progressed(&self) -> u64 {
+        self.progressed
+    }
+
+    // laplace-smoothed reward per selection; unexplored mutators start out even
+    fn score(&self) -> f64 {
+        (1.0 + self.added as f64 + PROGRESS_BONUS * self.progressed as f64)
+            / (1.0 + self.selected as f64)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MutatorSuccessMetadata {
+    stats: Vec<MutatorStats>,
+}
+
+impl_serdeany!(MutatorSuccessMetadata);
+
+impl MutatorSuccessMetadata {
+    pub fn stats(&self) -> &[MutatorStats] {
+        &self.stats
+    }
+}
 
 pub struct RandomPreferenceMutator<MT> {
     mutators: MT,
-    weights: Vec<MutationId>,
+    weights: Vec<usize>,
     total_weight: usize,
     until_reweight: usize,
+    last: Option<(usize, bool)>,
 }
 
 impl<MT> Named for RandomPreferenceMutator<MT> {
@@ -264,29 +867,61 @@ impl<MT> RandomPreferenceMutator<MT> {
             weights: Vec::new(),
             total_weight: 0,
             until_reweight: 0,
+            last: None,
         }
     }
 }
 
-impl<I, MT, S> Mutator<I, S> for RandomPreferenceMutator<MT>
+// each mutator's share of the selections, relative to the best scoring one
+fn weights(stats: &[MutatorStats]) -> Vec<usize> {
+    let best = stats
+        .iter()
+        .map(MutatorStats::score)
+        .fold(f64::MIN_POSITIVE, f64::max);
+    stats
+        .iter()
+        .map(|stats| 1 + (stats.score() / best * (WEIGHT_PRECISION - 1) as f64).round() as usize)
+        .collect()
+}
+
+fn filled_targets(input: &HallucinatedSokobanInput, board: &StaticBoard) -> usize {
+    input
+        .try_hallucinated()
+        .map_or(0, |hallucinated| hallucinated.filled(board))
+}
+
+impl<MT, S> Mutator<HallucinatedSokobanInput, S> for RandomPreferenceMutator<MT>
 where
-    MT: MutatorsTuple<I, S>,
-    S: HasCorpus + HasRand,
+    MT: MutatorsTuple<HallucinatedSokobanInput, S>,
+    S: HasCorpus + HasMetadata + HasRand,
 {
     fn mutate(
         &mut self,
         state: &mut S,
-        input: &mut I,
+        input: &mut HallucinatedSokobanInput,
         stage_idx: i32,
     ) -> Result<MutationResult, Error> {
+        if !state.has_metadata::<MutatorSuccessMetadata>() {
+            let stats = self
+                .mutators
+                .names()
+                .into_iter()
+                .map(|name| MutatorStats {
+                    name: name.to_string(),
+                    ..MutatorStats::default()
+                })
+                .collect();
+            state.add_metadata(MutatorSuccessMetadata { stats });
+        }
+
         if self.until_reweight == 0 {
             self.until_reweight = REWEIGHT_FREQUENCY;
             self.total_weight = 0;
             self.weights.clear();
-            for i in 0..self.mutators.len() {
-                let amount = 1 + state.rand_mut().below(WEIGHT_PRECISION) as usize;
-                self.weights
-                    .extend(std::iter::repeat(MutationId::from(i)).take(amount));
+
+            let stats = &state.metadata::<MutatorSuccessMetadata>()?.stats;
+            for (i, amount) in weights(stats).into_iter().enumerate() {
+                self.weights.extend(std::iter::repeat_n(i, amount));
                 self.total_weight += amount;
             }
         } else {
@@ -296,6 +931,128 @@ where
         let idx = state.rand_mut().below(self.total_weight as u64) as usize;
         let idx = self.weights[idx];
 
-        self.mutators.get_and_mutate(idx, state, input, stage_idx)
+        let board = state.metadata::<InitialPuzzleMetadata>()?.board().clone();
+        let before = filled_targets(input, &board);
+        let result =
+            self.mutators
+                .get_and_mutate(MutationId::from(idx), state, input, stage_idx)?;
+
+        let stats = &mut state.metadata_mut::<MutatorSuccessMetadata>()?.stats[idx];
+        stats.selected += 1;
+        if result == MutationResult::Skipped {
+            stats.skipped += 1;
+            self.last = None;
+        } else {
+            self.last = Some((idx, filled_targets(input, &board) > before));
+        }
+
+        Ok(result)
+    }
+
+    fn post_exec(
+        &mut self,
+        state: &mut S,
+        stage_idx: i32,
+        corpus_idx: Option<CorpusId>,
+    ) -> Result<(), Error> {
+        if let Some((idx, progressed)) = self.last.take() {
+            if corpus_idx.is_some() {
+                let stats = &mut state.metadata_mut::<MutatorSuccessMetadata>()?.stats[idx];
+                stats.added += 1;
+                if progressed {
+                    stats.progressed += 1;
+                }
+            }
+            self.mutators
+                .get_and_post_exec(idx, state, stage_idx, corpus_idx)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::board::StaticBoard;
+    use crate::mutators::{
+        weights, CandidateScores, MutatorStats, SokobanRemainingMutationsMetadata, UCB_EXPLORATION,
+        WEIGHT_PRECISION,
+    };
+    use sokoban::Direction::{Down, Left, Right, Up};
+    use sokoban::State as SokobanState;
+
+    #[test]
+    fn test_straight_pushes_stop_before_dead_squares() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#########
+#___m___#
+#_______#
+#_______#
+#x_____.#
+#########
+"#[..],
+        )
+        .unwrap();
+        let board = StaticBoard::new(&puzzle);
+        let remaining = SokobanRemainingMutationsMetadata::new(&board, &board.compact(&puzzle));
+
+        let mut pushes = remaining.straight_pushes_remaining.clone();
+        pushes.sort_by_key(|&(_, direction, distance)| (direction as usize, distance));
+        // the corners at either end of the top row are dead, the bottom row is not
+        let mut expected = vec![
+            ((1, 4), Left, 2),
+            ((1, 4), Right, 2),
+            ((1, 4), Down, 2),
+            ((1, 4), Down, 3),
+        ];
+        expected.sort_by_key(|&(_, direction, distance)| (direction as usize, distance));
+        assert_eq!(pushes, expected);
+        assert!(!pushes.iter().any(|&(_, direction, _)| direction == Up));
+    }
+
+    #[test]
+    fn test_candidate_scores_ucb() {
+        let mut scores = CandidateScores::default();
+        // untried candidates come first, the later one on a tie
+        assert_eq!(scores.best(&['a', 'b']), Some(1));
+        assert_eq!(scores.best(&[]), None);
+
+        scores.reward('a', 1.0);
+        scores.reward('b', 0.0);
+        assert_eq!(scores.best(&['a', 'b', 'c']), Some(2));
+        assert_eq!(scores.best(&['a', 'b']), Some(0));
+
+        // the mean reward plus the exploration bonus for trying it once in three
+        scores.reward('a', 0.0);
+        let expected = 0.5 + UCB_EXPLORATION * (3f64.ln() / 2.0).sqrt();
+        assert!((scores.ucb(&'a') - expected).abs() < 1e-9);
+
+        // enough failures and the rarely tried candidate wins on exploration
+        for _ in 0..20 {
+            scores.reward('a', 0.0);
+        }
+        assert_eq!(scores.best(&['a', 'b']), Some(1));
+    }
+
+    #[test]
+    fn test_mutator_weights() {
+        let stats = |selected, added, progressed| MutatorStats {
+            selected,
+            added,
+            progressed,
+            ..MutatorStats::default()
+        };
+
+        // unexplored mutators start out even
+        let fresh = [stats(0, 0, 0), stats(0, 0, 0)];
+        assert_eq!(fresh[0].score(), 1.0);
+        assert_eq!(weights(&fresh), [WEIGHT_PRECISION as usize; 2]);
+
+        // one lucky selection doesn't lock in, and progress counts for more than additions
+        let tried = [stats(1, 1, 0), stats(9, 0, 0), stats(3, 1, 1)];
+        assert_eq!(tried[0].score(), 1.0);
+        assert_eq!(tried[1].score(), 0.1);
+        assert_eq!(tried[2].score(), 1.5);
+        assert_eq!(weights(&tried), [43, 5, 64]);
     }
 }
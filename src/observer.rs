@@ -1,15 +1,22 @@
-use crate::util::hash_sokoban_state;
+use crate::board::{CompactState, StaticBoard};
 use libafl::inputs::UsesInput;
 use libafl::observers::{Observer, ObserverWithHashField};
 use libafl::Error;
 use libafl_bolts::Named;
 use serde::{Deserialize, Serialize};
 use sokoban::State as SokobanState;
+use std::cell::OnceCell;
+use std::sync::Arc;
 
+// holds the compact state; most feedbacks want the full one, so it is expanded once on demand
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SokobanStateObserver {
-    last_state: Option<SokobanState>,
+    last_state: Option<CompactState>,
+    #[serde(skip)]
+    expanded: OnceCell<SokobanState>,
+    board: Arc<StaticBoard>,
     include_player: bool,
+    normalize_player: bool,
     name: String,
 }
 
@@ -20,21 +27,40 @@ impl Named for SokobanStateObserver {
 }
 
 impl SokobanStateObserver {
-    pub fn new(name: &str, include_player: bool) -> Self {
+    pub fn new(name: &str, board: Arc<StaticBoard>, include_player: bool) -> Self {
         Self {
             last_state: None,
+            expanded: OnceCell::new(),
+            board,
             include_player,
+            normalize_player: false,
             name: name.to_string(),
         }
     }
 
-    pub fn replace(&mut self, state: SokobanState) -> Option<SokobanState> {
+    // hashes the player's region rather than the square it stands on
+    pub fn with_normalized_player(mut self) -> Self {
+        self.normalize_player = true;
+        self
+    }
+
+    pub fn replace(&mut self, state: CompactState) -> Option<CompactState> {
+        self.expanded.take();
         self.last_state.replace(state)
     }
 
-    pub fn last_state(&self) -> Option<&SokobanState> {
+    pub fn board(&self) -> &StaticBoard {
+        &self.board
+    }
+
+    pub fn last_compact(&self) -> Option<&CompactState> {
         self.last_state.as_ref()
     }
+
+    pub fn last_state(&self) -> Option<&SokobanState> {
+        let compact = self.last_state.as_ref()?;
+        Some(self.expanded.get_or_init(|| self.board.expand(compact)))
+    }
 }
 
 impl<S> Observer<S> for SokobanStateObserver
@@ -43,20 +69,77 @@ where
 {
     fn flush(&mut self) -> Result<(), Error> {
         self.last_state = None;
+        self.expanded.take();
         Ok(())
     }
 
     fn pre_exec(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
         self.last_state = None;
+        self.expanded.take();
+        Ok(())
+    }
+}
+
+// records the states along the way, one per push; only filled in when the executor is asked to
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SokobanTrajectoryObserver {
+    trajectory: Vec<CompactState>,
+    board: Arc<StaticBoard>,
+    name: String,
+}
+
+impl Named for SokobanTrajectoryObserver {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl SokobanTrajectoryObserver {
+    pub fn new(name: &str, board: Arc<StaticBoard>) -> Self {
+        Self {
+            trajectory: Vec::new(),
+            board,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn replace(&mut self, trajectory: Vec<CompactState>) -> Vec<CompactState> {
+        core::mem::replace(&mut self.trajectory, trajectory)
+    }
+
+    pub fn board(&self) -> &StaticBoard {
+        &self.board
+    }
+
+    pub fn trajectory(&self) -> &[CompactState] {
+        &self.trajectory
+    }
+}
+
+impl<S> Observer<S> for SokobanTrajectoryObserver
+where
+    S: UsesInput,
+{
+    fn flush(&mut self) -> Result<(), Error> {
+        self.trajectory.clear();
+        Ok(())
+    }
+
+    fn pre_exec(&mut self, _state: &mut S, _input: &S::Input) -> Result<(), Error> {
+        self.trajectory.clear();
         Ok(())
     }
 }
 
 impl ObserverWithHashField for SokobanStateObserver {
     fn hash(&self) -> Option<u64> {
-        self.last_state
-            .as_ref()
-            .map(|state| hash_sokoban_state(state, self.include_player))
+        self.last_state.as_ref().map(|state| {
+            if self.normalize_player {
+                state.region_hash(&self.board)
+            } else {
+                state.state_hash(&self.board, self.include_player)
+            }
+        })
     }
 }
 
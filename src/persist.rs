@@ -0,0 +1,267 @@
+use crate::input::SokobanInput;
+use libafl::corpus::ondisk::OnDiskMetadata;
+use libafl::corpus::{Corpus, Testcase};
+use libafl::inputs::Input;
+use libafl::state::HasMetadata;
+use libafl::Error;
+use libafl_bolts::serdeany::SerdeAnyMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sokoban::State as SokobanState;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// the puzzle an output directory belongs to
+pub const PUZZLE_FILE: &str = "puzzle.json";
+pub const QUEUE_DIR: &str = "queue";
+pub const SOLUTIONS_DIR: &str = "solutions";
+// where the entries wait while a resume adds them again, so that being stopped halfway loses none
+const STAGING_DIR: &str = ".resuming";
+// the corpora don't count the executions, so we keep count next to them
+const EXECUTIONS_FILE: &str = "executions.json";
+
+// what the on-disk corpora write next to each input
+#[derive(Deserialize)]
+struct SavedMetadata {
+    metadata: SerdeAnyMap,
+    #[allow(dead_code)]
+    exec_time: Option<Duration>,
+    #[allow(dead_code)]
+    executions: usize,
+}
+
+// the on-disk corpora only write the metadata when an entry is added, so anything we change
+// afterwards (e.g. the remaining mutations) has to be written out again
+pub fn save_testcase_metadata(testcase: &Testcase<SokobanInput>) -> Result<(), Error> {
+    let Some(path) = testcase.metadata_path() else {
+        return Ok(());
+    };
+    let mut tmp = path.clone();
+    tmp.set_extension("tmp");
+    serde_json::to_writer_pretty(
+        File::create(&tmp)?,
+        &OnDiskMetadata {
+            metadata: testcase.metadata_map(),
+            exec_time: testcase.exec_time(),
+            executions: testcase.executions(),
+        },
+    )?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+// moves the files of an on-disk corpus out of the way so that its entries can be added again, and
+// reads back every entry staged so far, shortest first; a resume that was stopped halfway left its
+// entries staged, and some of them in the corpus again
+fn stage_saved_entries(
+    dir: &Path,
+    staging: &Path,
+) -> Result<Vec<(SokobanInput, SerdeAnyMap)>, Error> {
+    if dir.exists() {
+        fs::create_dir_all(staging)?;
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if !path.is_file() {
+                continue;
+            }
+            if name.ends_with(".lafl_lock") {
+                fs::remove_file(&path)?;
+            } else {
+                fs::rename(&path, staging.join(name))?;
+            }
+        }
+    }
+
+    let mut entries = Vec::new();
+    if !staging.exists() {
+        return Ok(entries);
+    }
+    for file in fs::read_dir(staging)? {
+        let path = file?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name.starts_with('.') || !path.is_file() {
+            continue;
+        }
+        let input = SokobanInput::from_file(&path)?;
+        let meta_path = staging.join(format!(".{name}.metadata"));
+        let metadata = if meta_path.exists() {
+            let saved: SavedMetadata = serde_json::from_reader(File::open(&meta_path)?)?;
+            saved.metadata
+        } else {
+            SerdeAnyMap::new()
+        };
+        entries.push((input, metadata));
+    }
+    entries.sort_by_cached_key(|(input, _)| (input.moves().len(), input.generate_name(0)));
+    Ok(entries)
+}
+
+// the puzzle saved in an output directory, if any
+pub fn load_puzzle(dir: &Path) -> Result<Option<SokobanState>, Error> {
+    let path = dir.join(PUZZLE_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_reader(File::open(path)?)?))
+}
+
+pub fn save_puzzle(dir: &Path, puzzle: &SokobanState) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+    serde_json::to_writer(File::create(dir.join(PUZZLE_FILE))?, puzzle)?;
+    Ok(())
+}
+
+// whatever an earlier run left in the output directory
+#[derive(Default)]
+pub struct SavedCampaign {
+    pub queue: Vec<(SokobanInput, SerdeAnyMap)>,
+    pub solutions: Vec<(SokobanInput, SerdeAnyMap)>,
+    // none if the earlier run didn't get to count them
+    pub executions: Option<usize>,
+    dir: Option<PathBuf>,
+}
+
+impl SavedCampaign {
+    // the entries are staged until finish_resume, so the corpora can be opened on the same
+    // directory meanwhile
+    pub fn take(dir: &Path) -> Result<Self, Error> {
+        let staging = dir.join(STAGING_DIR);
+        let executions_path = dir.join(EXECUTIONS_FILE);
+        let executions = if executions_path.exists() {
+            Some(serde_json::from_reader(File::open(executions_path)?)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            queue: stage_saved_entries(&dir.join(QUEUE_DIR), &staging.join(QUEUE_DIR))?,
+            solutions: stage_saved_entries(&dir.join(SOLUTIONS_DIR), &staging.join(SOLUTIONS_DIR))?,
+            executions,
+            dir: Some(dir.to_path_buf()),
+        })
+    }
+
+    // every entry has been added again, so the staged copies can go
+    pub fn finish_resume(&self) -> Result<(), Error> {
+        if let Some(dir) = &self.dir {
+            let staging = dir.join(STAGING_DIR);
+            if staging.exists() {
+                fs::remove_dir_all(staging)?;
+            }
+        }
+        Ok(())
+    }
+
+    // a run resuming from a checkpoint takes the entries from it instead, so the directory is only
+    // kept up to date
+    pub fn attach(dir: &Path) -> Self {
+        Self {
+            dir: Some(dir.to_path_buf()),
+            ..Self::default()
+        }
+    }
+
+    // puts the corpora back as they were at the checkpoint: entries added since are deleted, and
+    // those removed since are written again; the metadata is left to save_corpus_metadata
+    pub fn restore_files(&self, files: &[(PathBuf, SokobanInput)]) -> Result<(), Error> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let mut kept = HashSet::new();
+        for (path, input) in files {
+            let name = path.file_name().unwrap().to_string_lossy();
+            let lock = path.with_file_name(format!(".{name}.lafl_lock"));
+            if !path.exists() {
+                input.to_file(path)?;
+                // so that no later entry takes the name
+                File::create(&lock)?;
+            }
+            kept.insert(path.with_file_name(format!(".{name}.metadata")));
+            kept.insert(lock);
+            kept.insert(path.clone());
+        }
+        for corpus in [QUEUE_DIR, SOLUTIONS_DIR] {
+            let corpus = dir.join(corpus);
+            if !corpus.exists() {
+                continue;
+            }
+            for file in fs::read_dir(corpus)? {
+                let path = file?.path();
+                if path.is_file() && !kept.contains(&path) {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save_executions(&self, executions: usize) -> Result<(), Error> {
+        if let Some(dir) = &self.dir {
+            let tmp = dir.join(format!("{EXECUTIONS_FILE}.tmp"));
+            serde_json::to_writer(File::create(&tmp)?, &executions)?;
+            fs::rename(tmp, dir.join(EXECUTIONS_FILE))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn save_corpus_metadata<C>(corpus: &C) -> Result<(), Error>
+where
+    C: Corpus<Input = SokobanInput>,
+{
+    for id in corpus.ids() {
+        save_testcase_metadata(&corpus.get(id)?.borrow())?;
+    }
+    Ok(())
+}
+
+// the whole campaign, to carry on exactly where it stopped; the states are borrowed when saving
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint<S, R> {
+    pub state: S,
+    // what the random number generator was first seeded with
+    pub seed: u64,
+    pub reverse: Option<R>,
+    pub elapsed: Duration,
+    // the entries of on-disk corpora only point at their files, which may have changed by the time
+    // we resume
+    pub files: Vec<(PathBuf, SokobanInput)>,
+}
+
+// where each entry of an on-disk corpus is kept, and what it holds
+pub fn corpus_files<C>(corpus: &C) -> Result<Vec<(PathBuf, SokobanInput)>, Error>
+where
+    C: Corpus<Input = SokobanInput>,
+{
+    let mut files = Vec::new();
+    for id in corpus.ids() {
+        let path = corpus.get(id)?.borrow().file_path().clone();
+        if let Some(path) = path {
+            files.push((path, corpus.cloned_input_for_id(id)?));
+        }
+    }
+    Ok(files)
+}
+
+// written next to the old checkpoint first, so that being stopped halfway doesn't lose it
+pub fn save_checkpoint<S, R>(path: &Path, checkpoint: &Checkpoint<S, R>) -> Result<(), Error>
+where
+    S: Serialize,
+    R: Serialize,
+{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, postcard::to_allocvec(checkpoint)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+pub fn load_checkpoint<S, R>(path: &Path) -> Result<Checkpoint<S, R>, Error>
+where
+    S: DeserializeOwned,
+    R: DeserializeOwned,
+{
+    Ok(postcard::from_bytes(&fs::read(path)?)?)
+}
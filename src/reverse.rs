@@ -0,0 +1,225 @@
+use libafl::corpus::{Corpus, InMemoryCorpus};
+use libafl::events::NopEventManager;
+use libafl::feedbacks::{ConstFeedback, NewHashFeedback};
+use libafl::schedulers::QueueScheduler;
+use libafl::stages::StdMutationalStage;
+use libafl::state::{HasCorpus, HasMetadata, StdState};
+use libafl::{Error, Evaluator, Fuzzer, StdFuzzer};
+use libafl_bolts::rands::{RomuDuoJrRand, StdRand};
+use libafl_bolts::tuples::tuple_list;
+use serde::{Deserialize, Serialize};
+use sokoban::State as SokobanState;
+
+use crate::executor::ReverseSokobanExecutor;
+use crate::input::ReverseSokobanInput;
+use crate::mutators::PullCrateMutator;
+use crate::observer::SokobanStateObserver;
+use crate::state::{InitialPuzzleMetadata, ReverseStatesMetadata};
+use crate::util::goal_states;
+
+type ReverseState = StdState<
+    ReverseSokobanInput,
+    InMemoryCorpus<ReverseSokobanInput>,
+    RomuDuoJrRand,
+    InMemoryCorpus<ReverseSokobanInput>,
+>;
+type ReverseObservers = (SokobanStateObserver, ());
+type ReverseExecutor = ReverseSokobanExecutor<ReverseObservers, ReverseState>;
+type ReverseManager = NopEventManager<ReverseState>;
+type ReverseFuzzer = StdFuzzer<
+    QueueScheduler<ReverseState>,
+    NewHashFeedback<SokobanStateObserver, ReverseState>,
+    ConstFeedback,
+    ReverseObservers,
+>;
+type ReverseStages = (
+    StdMutationalStage<
+        ReverseExecutor,
+        ReverseManager,
+        ReverseSokobanInput,
+        PullCrateMutator,
+        ReverseFuzzer,
+    >,
+    (),
+);
+
+// what a reverse campaign has found so far; the state is borrowed when saving it
+#[derive(Serialize, Deserialize)]
+pub struct ReverseCheckpoint<S = ReverseState> {
+    state: S,
+    synced: usize,
+}
+
+// a second campaign which pulls crates off the targets, starting from the solved puzzle
+pub struct ReverseCampaign {
+    solved: SokobanState,
+    fuzzer: ReverseFuzzer,
+    executor: ReverseExecutor,
+    state: ReverseState,
+    stages: ReverseStages,
+    mgr: ReverseManager,
+    synced: usize,
+}
+
+impl ReverseCampaign {
+    // none if the puzzle has no solved configuration to start from
+    pub fn new(initial: &SokobanState, seed: u64) -> Result<Option<Self>, Error> {
+        Self::with_checkpoint(initial, seed, None)
+    }
+
+    // carries on from a checkpoint taken on the same puzzle
+    pub fn resume(
+        initial: &SokobanState,
+        checkpoint: ReverseCheckpoint,
+    ) -> Result<Option<Self>, Error> {
+        // the checkpoint carries on with its own random state
+        Self::with_checkpoint(initial, 0, Some(checkpoint))
+    }
+
+    fn with_checkpoint(
+        initial: &SokobanState,
+        seed: u64,
+        checkpoint: Option<ReverseCheckpoint>,
+    ) -> Result<Option<Self>, Error> {
+        let goals = goal_states(initial);
+        let Some(solved) = goals.first().cloned() else {
+            return Ok(None);
+        };
+
+        let initial = InitialPuzzleMetadata::new(solved.clone());
+        let board = initial.board().clone();
+        // pulls which only move the player around within its region aren't new
+        let reverse_obs = SokobanStateObserver::new("reverse_state", board.clone(), true)
+            .with_normalized_player();
+
+        let mut feedback = NewHashFeedback::new(&reverse_obs);
+        let mut objective = ConstFeedback::new(false);
+
+        let executor = ReverseSokobanExecutor::new(board, solved.clone(), tuple_list!(reverse_obs));
+
+        // the checkpoint already holds the hashes the feedback has seen
+        let resumed = checkpoint.is_some();
+        let (state, synced) = match checkpoint {
+            Some(checkpoint) => (checkpoint.state, checkpoint.synced),
+            None => {
+                let mut state = StdState::new(
+                    StdRand::with_seed(seed),
+                    InMemoryCorpus::new(),
+                    InMemoryCorpus::new(),
+                    &mut feedback,
+                    &mut objective,
+                )?;
+                state.add_metadata(initial);
+                (state, 0)
+            }
+        };
+
+        let mut campaign = Self {
+            solved,
+            fuzzer: StdFuzzer::new(QueueScheduler::new(), feedback, objective),
+            executor,
+            state,
+            stages: tuple_list!(StdMutationalStage::new(PullCrateMutator)),
+            mgr: NopEventManager::new(),
+            synced,
+        };
+
+        if !resumed {
+            for goal in goals {
+                let _ = campaign.fuzzer.evaluate_input(
+                    &mut campaign.state,
+                    &mut campaign.executor,
+                    &mut campaign.mgr,
+                    ReverseSokobanInput::new(goal.player()),
+                )?;
+            }
+        }
+
+        Ok(Some(campaign))
+    }
+
+    pub fn checkpoint(&self) -> ReverseCheckpoint<&ReverseState> {
+        ReverseCheckpoint {
+            state: &self.state,
+            synced: self.synced,
+        }
+    }
+
+    pub fn fuzz_one(&mut self) -> Result<(), Error> {
+        self.fuzzer.fuzz_one(
+            &mut self.stages,
+            &mut self.executor,
+            &mut self.state,
+            &mut self.mgr,
+        )?;
+        Ok(())
+    }
+
+    pub fn found(&self) -> usize {
+        self.state.corpus().count()
+    }
+
+    // publishes the reverse states found since the last sync, so forward entries can meet them
+    pub fn sync(&mut self, reverse_states: &mut ReverseStatesMetadata) -> Result<(), Error> {
+        // the queue scheduler never removes entries, so the unsynced ones are the last ones
+        let count = self.state.corpus().count();
+        if count == self.synced {
+            return Ok(());
+        }
+        let mut next = Some(self.state.corpus().nth(self.synced));
+        while let Some(id) = next {
+            let input = self.state.corpus().cloned_input_for_id(id)?;
+            let reached = input
+                .replay(&self.solved)
+                .expect("Invalid sequence of pulls in reverse corpus!");
+            reverse_states.insert(&reached, input.forward_moves());
+            next = self.state.corpus().next(id);
+        }
+        self.synced = count;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::reverse::{ReverseCampaign, ReverseCheckpoint};
+    use crate::state::ReverseStatesMetadata;
+    use sokoban::State as SokobanState;
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let puzzle = SokobanState::parse(
+            &br#"
+########
+#______#
+#_m__m_#
+#x_.__.#
+########
+"#[..],
+        )
+        .unwrap();
+        let mut campaign = ReverseCampaign::new(&puzzle, 7).unwrap().unwrap();
+        for _ in 0..20 {
+            campaign.fuzz_one().unwrap();
+        }
+        campaign
+            .sync(&mut ReverseStatesMetadata::default())
+            .unwrap();
+
+        let bytes = postcard::to_allocvec(&campaign.checkpoint()).unwrap();
+        let checkpoint: ReverseCheckpoint = postcard::from_bytes(&bytes).unwrap();
+        let mut resumed = ReverseCampaign::resume(&puzzle, checkpoint)
+            .unwrap()
+            .unwrap();
+        assert_eq!(resumed.found(), campaign.found());
+        assert_eq!(resumed.synced, campaign.synced);
+
+        // it carries on with the same random state and the same hashes seen, so it finds the same
+        for _ in 0..20 {
+            campaign.fuzz_one().unwrap();
+            resumed.fuzz_one().unwrap();
+        }
+        assert_eq!(resumed.found(), campaign.found());
+        assert!(campaign.found() > campaign.synced);
+    }
+}
@@ -8,8 +8,11 @@ use libafl::Error;
 
 use crate::input::SokobanInput;
 use crate::mutators::SokobanRemainingMutationsMetadata;
-use crate::state::InitialPuzzleMetadata;
-use crate::util::find_crates;
+use crate::state::{
+    replay_cached, FavouredMetadata, InitialPuzzleMetadata, NewTargetsMetadata, Rebase,
+    RebaseMetadata, StatePathsMetadata,
+};
+use crate::util::hash_sokoban_state;
 
 pub struct SokobanWeightScheduler<S> {
     phantom: PhantomData<S>,
@@ -38,28 +41,57 @@ where
     S: State<Input = SokobanInput> + HasCorpus + HasMetadata + HasRand + HasTestcase,
 {
     fn on_add(&mut self, state: &mut Self::State, idx: CorpusId) -> Result<(), Error> {
+        let parent = *state.corpus().current();
         let mut testcase = state.testcase_mut(idx)?;
-        let input = testcase.load_input(state.corpus())?;
-        let hallucinated = input
-            .moves()
-            .iter()
-            .copied()
-            .try_fold(
-                state
-                    .metadata::<InitialPuzzleMetadata>()
-                    .unwrap()
-                    .initial()
-                    .clone(),
-                |puzzle, direction| puzzle.move_player(direction),
-            )
-            .unwrap();
+        if let Some(parent) = parent {
+            testcase.set_parent_id(parent);
+        }
+        let favoured = testcase.has_metadata::<NewTargetsMetadata>();
+        let moves = testcase.load_input(state.corpus())?.moves().to_vec();
+        drop(testcase);
 
-        let crates = find_crates(&hallucinated);
+        let (hallucinated, _) = replay_cached(state, &moves)?.unwrap();
+        let board = state.metadata::<InitialPuzzleMetadata>()?.board().clone();
+        let hash = hallucinated.state_hash(&board, true);
 
-        let tc_meta = SokobanRemainingMutationsMetadata::new(&crates, hallucinated.targets());
+        let tc_meta = SokobanRemainingMutationsMetadata::new(&board, &hallucinated);
+        state.testcase_mut(idx)?.add_metadata(tc_meta);
 
-        testcase.add_metadata(tc_meta);
-        drop(testcase);
+        if favoured {
+            state.metadata_mut::<FavouredMetadata>()?.push(idx);
+        }
+        let paths = state.metadata_mut::<StatePathsMetadata>()?;
+        let mut inherited = parent.and_then(|parent| paths.adopt(parent, idx));
+        let replaced = paths.claim(hash, idx, &moves);
+
+        if let Some(parent) = parent {
+            // the parent may have been flagged while it was being fuzzed
+            if let Ok(pending) = state.testcase(parent)?.metadata::<RebaseMetadata>() {
+                state.testcase_mut(idx)?.add_metadata(pending.clone());
+            }
+        }
+        if let Some(rebase) = inherited.take() {
+            flag_for_rebase(state, idx, rebase)?;
+        }
+
+        if let Some((old, rebase)) = replaced {
+            let descendants = state.metadata::<StatePathsMetadata>()?.descendants(old);
+            for descendant in descendants {
+                if descendant != idx && state.corpus().get(descendant).is_ok() {
+                    flag_for_rebase(state, descendant, rebase.clone())?;
+                }
+            }
+
+            if parent == Some(old) {
+                // we're still fuzzing it; let the scheduler drop it once we're done
+                state
+                    .testcase_mut(old)?
+                    .metadata_mut::<SokobanRemainingMutationsMetadata>()?
+                    .clear();
+            } else if state.corpus().get(old).is_ok() {
+                remove_entry(state, old)?;
+            }
+        }
 
         Ok(())
     }
@@ -72,13 +104,22 @@ where
             drop(testcase);
 
             if remaining == 0 {
-                state.corpus_mut().remove(current)?;
+                remove_entry(state, current)?;
             } else {
                 return Ok(current); // no change; keep fuzzing!
             }
         };
 
-        let next = state.corpus().first().ok_or_else(|| {
+        // favoured entries may have been exhausted or replaced in the meantime
+        let next = loop {
+            let Some(favoured) = state.metadata_mut::<FavouredMetadata>()?.pop() else {
+                break state.corpus().first();
+            };
+            if state.corpus().get(favoured).is_ok() {
+                break Some(favoured);
+            }
+        };
+        let next = next.ok_or_else(|| {
             self.set_current_scheduled(state, None).unwrap();
             Error::key_not_found(format!(
                 "Missing corpus entry; is the corpus empty? Reported size: {}",
@@ -86,6 +127,132 @@ where
             ))
         })?;
         self.set_current_scheduled(state, Some(next))?;
+        rebase(state, next)?;
         Ok(next)
     }
 }
+
+// drops the entry from the corpus, and from the paths kept for rebasing
+fn remove_entry<S>(state: &mut S, idx: CorpusId) -> Result<(), Error>
+where
+    S: HasCorpus + HasMetadata,
+{
+    state.corpus_mut().remove(idx)?;
+    state.metadata_mut::<StatePathsMetadata>()?.remove(idx);
+    Ok(())
+}
+
+fn flag_for_rebase<S>(state: &mut S, idx: CorpusId, rebase: Rebase) -> Result<(), Error>
+where
+    S: HasTestcase,
+{
+    let mut testcase = state.testcase_mut(idx)?;
+    if !testcase.has_metadata::<RebaseMetadata>() {
+        testcase.add_metadata(RebaseMetadata::default());
+    }
+    testcase.metadata_mut::<RebaseMetadata>()?.push(rebase);
+    Ok(())
+}
+
+// swaps a replaced ancestor's path out for the shorter one; the entry still ends in the same state
+fn rebase<S>(state: &mut S, idx: CorpusId) -> Result<(), Error>
+where
+    S: State<Input = SokobanInput> + HasCorpus + HasMetadata + HasTestcase,
+{
+    let mut testcase = state.testcase_mut(idx)?;
+    let Some(pending) = testcase.metadata_map_mut().remove::<RebaseMetadata>() else {
+        return Ok(());
+    };
+    let initial = state.metadata::<InitialPuzzleMetadata>()?.initial();
+    let moves = testcase.load_input(state.corpus())?.moves().to_vec();
+    let Some(rebased) = pending.apply(&initial, &moves) else {
+        return Ok(());
+    };
+
+    let reached = rebased
+        .iter()
+        .copied()
+        .try_fold(initial, |puzzle, direction| puzzle.move_player(direction))
+        .unwrap();
+    let length = rebased.len();
+    testcase.set_input(SokobanInput::new(rebased));
+    drop(testcase);
+
+    state.metadata_mut::<StatePathsMetadata>()?.rebased(
+        hash_sokoban_state(&reached, true),
+        idx,
+        length,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::input::SokobanInput;
+    use crate::mutators::SokobanRemainingMutationsMetadata;
+    use crate::scheduler::SokobanWeightScheduler;
+    use crate::state::{
+        FavouredMetadata, InitialPuzzleMetadata, NewTargetsMetadata, PrefixCacheMetadata,
+        StatePathsMetadata,
+    };
+    use libafl::corpus::{Corpus, HasTestcase, InMemoryCorpus, Testcase};
+    use libafl::schedulers::Scheduler;
+    use libafl::state::{HasCorpus, HasMetadata, StdState};
+    use libafl_bolts::rands::StdRand;
+    use sokoban::Direction::{Down, Left, Right};
+    use sokoban::State as SokobanState;
+
+    #[test]
+    fn test_favoured_first() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#######
+#_____#
+#__x__#
+#___m.#
+#######
+"#[..],
+        )
+        .unwrap();
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<SokobanInput>::new(),
+            InMemoryCorpus::new(),
+            &mut (),
+            &mut (),
+        )
+        .unwrap();
+        state.add_metadata(InitialPuzzleMetadata::new(puzzle));
+        state.add_metadata(PrefixCacheMetadata::default());
+        state.add_metadata(StatePathsMetadata::default());
+        state.add_metadata(FavouredMetadata::default());
+        let mut scheduler = SokobanWeightScheduler::new();
+
+        let mut add = |moves, favoured| {
+            let mut testcase = Testcase::new(SokobanInput::new(moves));
+            if favoured {
+                testcase.add_metadata(NewTargetsMetadata);
+            }
+            let id = state.corpus_mut().add(testcase).unwrap();
+            scheduler.on_add(&mut state, id).unwrap();
+            id
+        };
+        let plain = add(vec![Left], false);
+        let favoured = add(vec![Right], true);
+        let replaced = add(vec![Down, Right], true);
+
+        // the favoured entry goes ahead of the queue, even though it was added later
+        assert_eq!(scheduler.next(&mut state).unwrap(), favoured);
+
+        // once it's exhausted, a favoured entry that has since left the corpus is passed over
+        state.corpus_mut().remove(replaced).unwrap();
+        state
+            .testcase_mut(favoured)
+            .unwrap()
+            .metadata_mut::<SokobanRemainingMutationsMetadata>()
+            .unwrap()
+            .clear();
+        assert_eq!(scheduler.next(&mut state).unwrap(), plain);
+        assert!(state.corpus().get(favoured).is_err());
+    }
+}
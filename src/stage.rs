@@ -0,0 +1,98 @@
+use crate::board::CompactState;
+use crate::budget::exhausted_budget;
+use crate::input::{HallucinatedSokobanInput, SokobanInput};
+use libafl::corpus::CorpusId;
+use libafl::mutators::{MutationResult, Mutator};
+use libafl::stages::mutational::DEFAULT_MUTATIONAL_MAX_ITERATIONS;
+use libafl::stages::Stage;
+use libafl::state::{HasCorpus, HasExecutions, HasMetadata, HasRand, UsesState};
+use libafl::{Error, Evaluator};
+use libafl_bolts::rands::Rand;
+use libafl_bolts::Named;
+use std::marker::PhantomData;
+
+// an executor which can take the state a mutator hallucinated alongside the input, and the states
+// after each push on the way, so that it doesn't have to replay the input to find them
+pub trait HallucinationExecutor {
+    // for the next execution only: the mutator which made the input, to blame for a bad input, and
+    // its hallucination, if it didn't play an illegal move
+    fn hallucinate(
+        &mut self,
+        mutator: &str,
+        hallucination: Option<(Vec<CompactState>, CompactState)>,
+    );
+
+    // whatever the last execution left unused; should always be None
+    fn take_hallucination(&mut self) -> Option<(Vec<CompactState>, CompactState)>;
+}
+
+// like a transforming StdMutationalStage, but hands the hallucinated state straight to the
+// executor rather than through the state metadata
+#[derive(Debug)]
+pub struct HallucinatingStage<E, EM, M, Z> {
+    mutator: M,
+    phantom: PhantomData<(E, EM, Z)>,
+}
+
+impl<E, EM, M, Z> HallucinatingStage<E, EM, M, Z> {
+    pub fn new(mutator: M) -> Self {
+        Self {
+            mutator,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<E, EM, M, Z> UsesState for HallucinatingStage<E, EM, M, Z>
+where
+    E: UsesState,
+{
+    type State = E::State;
+}
+
+impl<E, EM, M, Z> Stage<E, EM, Z> for HallucinatingStage<E, EM, M, Z>
+where
+    E: UsesState + HallucinationExecutor,
+    EM: UsesState<State = E::State>,
+    M: Mutator<HallucinatedSokobanInput, E::State> + Named,
+    Z: Evaluator<E, EM, State = E::State>,
+    E::State: HasCorpus<Input = SokobanInput> + HasExecutions + HasMetadata + HasRand,
+{
+    #[allow(clippy::cast_possible_wrap)]
+    fn perform(
+        &mut self,
+        fuzzer: &mut Z,
+        executor: &mut E,
+        state: &mut E::State,
+        manager: &mut EM,
+        corpus_idx: CorpusId,
+    ) -> Result<(), Error> {
+        let num = 1 + state.rand_mut().below(DEFAULT_MUTATIONAL_MAX_ITERATIONS);
+
+        let input = HallucinatedSokobanInput::from_corpus(corpus_idx, state)?;
+
+        for i in 0..num {
+            // a single stage can run many executions, so it mustn't overrun the budget either
+            if exhausted_budget(state).is_some() {
+                break;
+            }
+            let mut input = input.clone();
+            if self.mutator.mutate(state, &mut input, i as i32)? == MutationResult::Skipped {
+                continue;
+            }
+
+            let (untransformed, hallucination) = input.into_parts();
+            executor.hallucinate(self.mutator.name(), hallucination);
+            let (_, corpus_idx) = fuzzer.evaluate_input(state, executor, manager, untransformed)?;
+            if executor.take_hallucination().is_some() {
+                return Err(Error::illegal_state(
+                    "the executor didn't consume the hallucinated state",
+                ));
+            }
+
+            self.mutator.post_exec(state, i as i32, corpus_idx)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,34 +1,937 @@
+use crate::board::{CompactState, StaticBoard};
+use crate::input::SokobanInput;
+use crate::util::{count_filled, count_pushes, crate_distance, go_to, hash_sokoban_state};
+use libafl::corpus::{Corpus, CorpusId, InMemoryCorpus, Testcase};
+use libafl::state::{HasCorpus, HasMetadata, HasSolutions};
+use libafl::Error;
 use libafl_bolts::impl_serdeany;
 use serde::{Deserialize, Serialize};
-use sokoban::State as SokobanState;
-use std::cell::{RefCell, RefMut};
+use sokoban::{Direction, State as SokobanState};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct InitialPuzzleMetadata {
-    initial: SokobanState,
+    board: Arc<StaticBoard>,
+    compact: CompactState,
 }
 
 impl_serdeany!(InitialPuzzleMetadata);
 
 impl InitialPuzzleMetadata {
     pub fn new(initial: SokobanState) -> Self {
-        Self { initial }
+        let board = Arc::new(StaticBoard::new(&initial));
+        let compact = board.compact(&initial);
+        Self { board, compact }
     }
 
-    pub fn initial(&self) -> &SokobanState {
-        &self.initial
+    // only kept compact, so expanded on every call
+    pub fn initial(&self) -> SokobanState {
+        self.board.expand(&self.compact)
     }
+
+    pub fn board(&self) -> &Arc<StaticBoard> {
+        &self.board
+    }
+
+    pub fn compact(&self) -> &CompactState {
+        &self.compact
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ReverseState {
+    player: (usize, usize),
+    solution: Vec<Direction>,
+}
+
+// how many reverse states forward entries can meet; the oldest make way for new ones
+const MAX_REVERSE_STATES: usize = 100_000;
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct ReverseStatesMetadata {
+    states: HashMap<u64, Vec<ReverseState>>,
+    // the configuration of every state, oldest first
+    inserted: VecDeque<u64>,
+}
+
+impl_serdeany!(ReverseStatesMetadata);
+
+impl ReverseStatesMetadata {
+    pub fn insert(&mut self, reached: &SokobanState, solution: Vec<Direction>) {
+        let hash = hash_sokoban_state(reached, false);
+        self.states.entry(hash).or_default().push(ReverseState {
+            player: reached.player(),
+            solution,
+        });
+        self.inserted.push_back(hash);
+
+        if self.inserted.len() > MAX_REVERSE_STATES {
+            let oldest = self.inserted.pop_front().unwrap();
+            if let Entry::Occupied(mut entry) = self.states.entry(oldest) {
+                entry.get_mut().remove(0);
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    // the moves which solve the puzzle from this state by walking into a known reverse state
+    pub fn meet(&self, forward: &SokobanState) -> Option<Vec<Direction>> {
+        for reverse in self.states.get(&hash_sokoban_state(forward, false))? {
+            if let Some(walk) = go_to(forward.player(), reverse.player, forward) {
+                let mut moves = Vec::from(walk);
+                moves.extend(&reverse.solution);
+
+                // guard against hash collisions
+                if moves
+                    .iter()
+                    .copied()
+                    .try_fold(forward.clone(), |puzzle, direction| {
+                        puzzle.move_player(direction)
+                    })
+                    .is_ok_and(|solved| solved.in_solution_state())
+                {
+                    return Some(moves);
+                }
+            }
+        }
+        None
+    }
+}
+
+// every combination of filled targets reached so far
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct FilledTargetsMetadata {
+    seen: HashSet<Vec<u64>>,
+}
+
+impl_serdeany!(FilledTargetsMetadata);
+
+impl FilledTargetsMetadata {
+    pub fn insert(&mut self, filled: Vec<u64>) -> bool {
+        self.seen.insert(filled)
+    }
+}
+
+// marks corpus entries which filled a new combination of targets
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct NewTargetsMetadata;
+
+impl_serdeany!(NewTargetsMetadata);
+
+// entries which filled a new combination of targets, to be fuzzed before the rest of the queue
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct FavouredMetadata {
+    queue: VecDeque<CorpusId>,
+}
+
+impl_serdeany!(FavouredMetadata);
+
+impl FavouredMetadata {
+    pub fn push(&mut self, id: CorpusId) {
+        self.queue.push_back(id);
+    }
+
+    pub fn pop(&mut self) -> Option<CorpusId> {
+        self.queue.pop_front()
+    }
+}
+
+// what a solution costs, and how far into the campaign it was found
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SolutionCostMetadata {
+    moves: usize,
+    pushes: usize,
+    found: Duration,
+}
+
+impl_serdeany!(SolutionCostMetadata);
+
+impl SolutionCostMetadata {
+    pub fn new(moves: usize, pushes: usize, found: Duration) -> Self {
+        Self {
+            moves,
+            pushes,
+            found,
+        }
+    }
+
+    pub fn moves(&self) -> usize {
+        self.moves
+    }
+
+    pub fn pushes(&self) -> usize {
+        self.pushes
+    }
+
+    pub fn found(&self) -> Duration {
+        self.found
+    }
+
+    // at least as good in every measure; identical costs dominate each other
+    fn dominates(&self, other: &Self, include_time: bool) -> bool {
+        self.moves <= other.moves
+            && self.pushes <= other.pushes
+            && (!include_time || self.found <= other.found)
+    }
+}
+
+// tags new solutions with their cost and evicts every solution dominated by another one
+pub fn update_solution_front<S>(
+    state: &mut S,
+    elapsed: Duration,
+    include_time: bool,
+) -> Result<(), Error>
+where
+    S: HasSolutions<Input = SokobanInput> + HasMetadata,
+{
+    let initial = state.metadata::<InitialPuzzleMetadata>()?.initial();
+    let mut costs = Vec::new();
+    for id in state.solutions().ids().collect::<Vec<_>>() {
+        let mut testcase = state.solutions().get(id)?.borrow_mut();
+        let cost = match testcase.metadata::<SolutionCostMetadata>() {
+            Ok(&cost) => cost,
+            Err(_) => {
+                let moves = testcase.input().as_ref().unwrap().moves();
+                let cost = SolutionCostMetadata {
+                    moves: moves.len(),
+                    pushes: count_pushes(&initial, moves).unwrap(),
+                    found: elapsed,
+                };
+                testcase.add_metadata(cost);
+                cost
+            }
+        };
+        costs.push((id, cost));
+    }
+
+    // of several identical solutions, the earliest one is kept
+    let dominated = costs
+        .iter()
+        .enumerate()
+        .filter(|&(i, (_, cost))| {
+            costs.iter().enumerate().any(|(j, (_, other))| {
+                j != i
+                    && other.dominates(cost, include_time)
+                    && (j < i || !cost.dominates(other, include_time))
+            })
+        })
+        .map(|(_, &(id, _))| id)
+        .collect::<Vec<_>>();
+    for id in dominated {
+        state.solutions_mut().remove(id)?;
+    }
+    Ok(())
+}
+
+// the solution on the front with the fewest moves, then the fewest pushes, then the earliest
+pub fn settled_solution<S>(state: &S) -> Result<Option<CorpusId>, Error>
+where
+    S: HasSolutions,
+{
+    let mut settled = None;
+    for id in state.solutions().ids() {
+        let cost = *state
+            .solutions()
+            .get(id)?
+            .borrow()
+            .metadata::<SolutionCostMetadata>()?;
+        let key = (cost.moves, cost.pushes, cost.found);
+        if settled.as_ref().is_none_or(|&(_, best)| key < best) {
+            settled = Some((id, key));
+        }
+    }
+    Ok(settled.map(|(id, _)| id))
+}
+
+// how close an unsolved corpus entry got
+pub struct PartialProgress {
+    pub moves: Vec<Direction>,
+    pub reached: SokobanState,
+    pub filled: usize,
+    pub distance: usize,
+}
+
+// the corpus entry with the most targets filled, then the crates nearest their targets, then the
+// fewest moves
+pub fn best_partial<S>(state: &mut S) -> Result<Option<PartialProgress>, Error>
+where
+    S: HasCorpus<Input = SokobanInput> + HasMetadata,
+{
+    let board = state.metadata::<InitialPuzzleMetadata>()?.board().clone();
+    let mut best: Option<PartialProgress> = None;
+    for id in state.corpus().ids().collect::<Vec<_>>() {
+        let moves = state.corpus().cloned_input_for_id(id)?.moves().to_vec();
+        let Ok((reached, _)) = replay_cached(state, &moves)? else {
+            continue;
+        };
+        let reached = board.expand(&reached);
+        let progress = PartialProgress {
+            filled: count_filled(&reached),
+            distance: crate_distance(&reached),
+            moves,
+            reached,
+        };
+        let key = |progress: &PartialProgress| {
+            (
+                std::cmp::Reverse(progress.filled),
+                progress.distance,
+                progress.moves.len(),
+            )
+        };
+        if best.as_ref().is_none_or(|best| key(&progress) < key(best)) {
+            best = Some(progress);
+        }
+    }
+    Ok(best)
+}
+
+// a shorter path to a state which some corpus entries pass through
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rebase {
+    hash: u64,
+    stale: usize,
+    replacement: Vec<Direction>,
+}
+
+impl Rebase {
+    // swaps the stale prefix of the moves for the replacement, if they really pass through the state
+    pub fn apply(&self, initial: &SokobanState, moves: &[Direction]) -> Option<Vec<Direction>> {
+        let reached = moves
+            .get(..self.stale)?
+            .iter()
+            .copied()
+            .try_fold(initial.clone(), |puzzle, direction| {
+                puzzle.move_player(direction)
+            })
+            .ok()?;
+        if hash_sokoban_state(&reached, true) != self.hash {
+            return None;
+        }
+        let mut rebased = self.replacement.clone();
+        rebased.extend_from_slice(&moves[self.stale..]);
+        Some(rebased)
+    }
+}
+
+// rebases waiting to be applied once the entry is next scheduled
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct RebaseMetadata {
+    pending: Vec<Rebase>,
+}
+
+impl_serdeany!(RebaseMetadata);
+
+impl RebaseMetadata {
+    pub fn push(&mut self, rebase: Rebase) {
+        self.pending.push(rebase);
+    }
+
+    // the deepest replaced ancestor covers every shallower one
+    pub fn apply(mut self, initial: &SokobanState, moves: &[Direction]) -> Option<Vec<Direction>> {
+        self.pending
+            .sort_by_key(|rebase| std::cmp::Reverse(rebase.stale));
+        self.pending
+            .iter()
+            .find_map(|rebase| rebase.apply(initial, moves))
+    }
+}
+
+// the fewest moves seen to reach each state, and which corpus entry got there that way
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct StatePathsMetadata {
+    best: HashMap<u64, (usize, Option<(CorpusId, usize)>)>,
+    children: HashMap<CorpusId, Vec<CorpusId>>,
+    parents: HashMap<CorpusId, CorpusId>,
+    replaced: HashMap<CorpusId, Rebase>,
+}
+
+impl_serdeany!(StatePathsMetadata);
+
+impl StatePathsMetadata {
+    // whether this is the first or the shortest path to the state so far
+    pub fn offer(&mut self, hash: u64, moves: usize) -> bool {
+        match self.best.get_mut(&hash) {
+            Some((best, _)) if *best <= moves => false,
+            Some((best, _)) => {
+                *best = moves;
+                true
+            }
+            None => {
+                self.best.insert(hash, (moves, None));
+                true
+            }
+        }
+    }
+
+    // makes the entry the holder of the state, returning the entry it replaces
+    pub fn claim(
+        &mut self,
+        hash: u64,
+        id: CorpusId,
+        moves: &[Direction],
+    ) -> Option<(CorpusId, Rebase)> {
+        let (best, holder) = self.best.entry(hash).or_insert((moves.len(), None));
+        *best = moves.len().min(*best);
+        if holder.is_some_and(|(_, length)| length <= moves.len()) {
+            // kept for some other reason than its path
+            return None;
+        }
+        let (previous, stale) = holder
+            .replace((id, moves.len()))
+            .filter(|&(previous, _)| previous != id)?;
+        let rebase = Rebase {
+            hash,
+            stale,
+            replacement: moves.to_vec(),
+        };
+        self.replaced.insert(previous, rebase.clone());
+        Some((previous, rebase))
+    }
+
+    // the entry was rebased and now reaches its state in fewer moves
+    pub fn rebased(&mut self, hash: u64, id: CorpusId, moves: usize) {
+        if let Some((best, Some((holder, length)))) = self.best.get_mut(&hash) {
+            if *holder == id {
+                *length = moves;
+                *best = moves.min(*best);
+            }
+        }
+    }
+
+    // records the parent of a new entry, and whether the parent has since been replaced
+    pub fn adopt(&mut self, parent: CorpusId, child: CorpusId) -> Option<Rebase> {
+        self.children.entry(parent).or_default().push(child);
+        self.parents.insert(child, parent);
+        self.replaced.get(&parent).cloned()
+    }
+
+    // the entry left the corpus; its children move up to its parent, so that they are still
+    // among the descendants of everything above it, and the states it held are up for grabs
+    pub fn remove(&mut self, id: CorpusId) {
+        self.replaced.remove(&id);
+        for (_, holder) in self.best.values_mut() {
+            if holder.is_some_and(|(holder, _)| holder == id) {
+                *holder = None;
+            }
+        }
+        let children = self.children.remove(&id).unwrap_or_default();
+        let parent = self.parents.remove(&id);
+        for &child in &children {
+            match parent {
+                Some(parent) => self.parents.insert(child, parent),
+                None => self.parents.remove(&child),
+            };
+        }
+        if let Some(parent) = parent {
+            let siblings = self.children.entry(parent).or_default();
+            siblings.retain(|&sibling| sibling != id);
+            siblings.extend(children);
+        }
+    }
+
+    pub fn descendants(&self, id: CorpusId) -> Vec<CorpusId> {
+        let mut descendants = Vec::new();
+        let mut queue = VecDeque::from([id]);
+        while let Some(next) = queue.pop_front() {
+            for &child in self.children.get(&next).into_iter().flatten() {
+                descendants.push(child);
+                queue.push_back(child);
+            }
+        }
+        descendants
+    }
+}
+
+// what was wrong with an input
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum InvalidMove {
+    // the move at this index is illegal on the board it was played on
+    Illegal {
+        index: usize,
+        board: SokobanState,
+    },
+    // the moves are legal, but don't reach the state or make the pushes the mutator hallucinated
+    Mismatch {
+        hallucinated: (SokobanState, usize),
+        reached: (SokobanState, usize),
+    },
 }
 
+// why an input was recorded as invalid, and which mutator made it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InvalidMoveMetadata {
+    why: InvalidMove,
+    mutator: Option<String>,
+}
+
+impl_serdeany!(InvalidMoveMetadata);
+
+impl InvalidMoveMetadata {
+    pub fn why(&self) -> &InvalidMove {
+        &self.why
+    }
+
+    pub fn mutator(&self) -> Option<&str> {
+        self.mutator.as_deref()
+    }
+}
+
+// inputs with illegal moves in them or wrong hallucinations; these only come from bugs in the
+// mutators
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InvalidInputsMetadata {
+    corpus: InMemoryCorpus<SokobanInput>,
+}
+
+impl_serdeany!(InvalidInputsMetadata);
+
+impl Default for InvalidInputsMetadata {
+    fn default() -> Self {
+        Self {
+            corpus: InMemoryCorpus::new(),
+        }
+    }
+}
+
+impl InvalidInputsMetadata {
+    pub fn add(
+        &mut self,
+        input: SokobanInput,
+        why: InvalidMove,
+        mutator: Option<String>,
+    ) -> Result<(), Error> {
+        let mut testcase = Testcase::new(input);
+        testcase.add_metadata(InvalidMoveMetadata { why, mutator });
+        self.corpus.add(testcase)?;
+        Ok(())
+    }
+
+    pub fn corpus(&self) -> &InMemoryCorpus<SokobanInput> {
+        &self.corpus
+    }
+}
+
+// states are cached after every this many moves
+const PREFIX_INTERVAL: usize = 16;
+const PREFIX_CACHE_CAPACITY: usize = 1 << 16;
+
+// FNV-1a over the moves, so the hash of each prefix falls out on the way
+const PREFIX_HASH_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const PREFIX_HASH_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn extend_prefix_hash(hash: u64, direction: Direction) -> u64 {
+    (hash ^ direction as u64).wrapping_mul(PREFIX_HASH_PRIME)
+}
+
+// the state some moves reach and the pushes made on the way, or the index of the first illegal
+// move and the state it was attempted on
+pub type Replayed = Result<(CompactState, usize), (usize, CompactState)>;
+
+// the states reached by recently replayed prefixes and the pushes made on the way, keyed by the
+// hash of the prefix; the prefix is kept too, since two of them may share a hash. It's only a
+// cache, so checkpoints leave it out
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[allow(clippy::type_complexity)]
+pub struct PrefixCacheMetadata {
+    #[serde(skip)]
+    states: HashMap<u64, (Box<[Direction]>, (CompactState, usize), u64)>,
+    // last use of each entry, oldest first
+    #[serde(skip)]
+    recency: BTreeMap<u64, u64>,
+    #[serde(skip)]
+    tick: u64,
+}
+
+impl_serdeany!(PrefixCacheMetadata);
+
+impl PrefixCacheMetadata {
+    fn get(&mut self, hash: u64, prefix: &[Direction]) -> Option<(CompactState, usize)> {
+        let (cached, state, used) = self.states.get_mut(&hash)?;
+        if **cached != *prefix {
+            return None;
+        }
+        self.recency.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.recency.insert(self.tick, hash);
+        Some(state.clone())
+    }
+
+    fn insert(&mut self, hash: u64, prefix: &[Direction], state: (CompactState, usize)) {
+        self.tick += 1;
+        if let Some((_, _, used)) = self.states.insert(hash, (prefix.into(), state, self.tick)) {
+            self.recency.remove(&used);
+        }
+        self.recency.insert(self.tick, hash);
+        if self.states.len() > PREFIX_CACHE_CAPACITY {
+            if let Some((_, evicted)) = self.recency.pop_first() {
+                self.states.remove(&evicted);
+            }
+        }
+    }
+
+    // replays the moves from the longest prefix we have cached; also returns how many moves that
+    // saved
+    pub fn replay(
+        &mut self,
+        board: &StaticBoard,
+        initial: &CompactState,
+        moves: &[Direction],
+    ) -> (usize, Replayed) {
+        let mut hash = PREFIX_HASH_OFFSET;
+        let mut hashes = Vec::with_capacity(moves.len() / PREFIX_INTERVAL);
+        for (i, &direction) in moves.iter().enumerate() {
+            hash = extend_prefix_hash(hash, direction);
+            if (i + 1) % PREFIX_INTERVAL == 0 {
+                hashes.push(hash);
+            }
+        }
+
+        let (start, (mut current, mut pushes)) = hashes
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(checkpoint, &hash)| {
+                let length = (checkpoint + 1) * PREFIX_INTERVAL;
+                self.get(hash, &moves[..length])
+                    .map(|state| (length, state))
+            })
+            .unwrap_or_else(|| (0, (initial.clone(), 0)));
+
+        for (i, &direction) in moves.iter().enumerate().skip(start) {
+            match current.move_player(board, direction) {
+                Some(pushed) => pushes += usize::from(pushed),
+                None => return (start, Err((i, current))),
+            }
+            if (i + 1) % PREFIX_INTERVAL == 0 {
+                self.insert(
+                    hashes[i / PREFIX_INTERVAL],
+                    &moves[..=i],
+                    (current.clone(), pushes),
+                );
+            }
+        }
+        (start, Ok((current, pushes)))
+    }
+}
+
+// replays the moves from the initial puzzle through the prefix cache, counting what it saved
+pub fn replay_cached<S>(state: &mut S, moves: &[Direction]) -> Result<Replayed, Error>
+where
+    S: HasMetadata,
+{
+    let initial = state.metadata::<InitialPuzzleMetadata>()?;
+    let (board, compact) = (initial.board().clone(), initial.compact().clone());
+    let (saved, replayed) = state
+        .metadata_mut::<PrefixCacheMetadata>()?
+        .replay(&board, &compact, moves);
+    if let Ok(stats) = state.metadata_mut::<CampaignStatsMetadata>() {
+        stats.record_prefix_lookup(saved, moves.len());
+    }
+    Ok(replayed)
+}
+
+// running totals behind the statistics reported by the campaign
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
-pub struct LastHallucinationMetadata {
-    hallucination: RefCell<Option<SokobanState>>,
+pub struct CampaignStatsMetadata {
+    executions: u64,
+    pushes: u64,
+    configurations: DistinctSketch,
+    deadlocks: HashMap<String, u64>,
+    skipped: HashMap<String, u64>,
+    push_to_failures: u64,
+    prefix_lookups: u64,
+    prefix_hits: u64,
+    prefix_moves: u64,
+    prefix_moves_saved: u64,
+}
+
+impl_serdeany!(CampaignStatsMetadata);
+
+impl CampaignStatsMetadata {
+    pub fn record_execution(&mut self, pushes: usize, configuration: u64) {
+        self.executions += 1;
+        self.pushes += pushes as u64;
+        self.configurations.insert(configuration);
+    }
+
+    pub fn record_deadlock(&mut self, rule: &str) {
+        bump(&mut self.deadlocks, rule);
+    }
+
+    pub fn record_skip(&mut self, mutator: &str) {
+        bump(&mut self.skipped, mutator);
+    }
+
+    pub fn record_push_to_failure(&mut self) {
+        self.push_to_failures += 1;
+    }
+
+    pub fn record_prefix_lookup(&mut self, saved: usize, moves: usize) {
+        self.prefix_lookups += 1;
+        if saved > 0 {
+            self.prefix_hits += 1;
+        }
+        self.prefix_moves += moves as u64;
+        self.prefix_moves_saved += saved as u64;
+    }
+
+    pub fn executions(&self) -> u64 {
+        self.executions
+    }
+
+    pub fn pushes(&self) -> u64 {
+        self.pushes
+    }
+
+    // an estimate, so that the statistics don't grow with the campaign
+    pub fn configurations(&self) -> usize {
+        self.configurations.estimate()
+    }
+
+    pub fn deadlocks(&self) -> &HashMap<String, u64> {
+        &self.deadlocks
+    }
+
+    pub fn skipped(&self) -> &HashMap<String, u64> {
+        &self.skipped
+    }
+
+    pub fn push_to_failures(&self) -> u64 {
+        self.push_to_failures
+    }
+
+    pub fn prefix_hits(&self) -> (u64, u64) {
+        (self.prefix_hits, self.prefix_lookups)
+    }
+
+    pub fn prefix_moves_saved(&self) -> (u64, u64) {
+        (self.prefix_moves_saved, self.prefix_moves)
+    }
+}
+
+// a HyperLogLog count of distinct hashes; 4096 registers keep it within a few percent
+const SKETCH_BITS: u32 = 12;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DistinctSketch {
+    registers: Vec<u8>,
+}
+
+impl Default for DistinctSketch {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; 1 << SKETCH_BITS],
+        }
+    }
 }
 
-impl_serdeany!(LastHallucinationMetadata);
+impl DistinctSketch {
+    pub fn insert(&mut self, value: u64) {
+        // the splitmix64 finalizer, so that neighbouring values spread over the registers
+        let mut hash = value.wrapping_add(0x9e3779b97f4a7c15);
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash ^= hash >> 31;
+
+        let index = (hash >> (64 - SKETCH_BITS)) as usize;
+        let rank = ((hash << SKETCH_BITS) | (1 << (SKETCH_BITS - 1))).leading_zeros() + 1;
+        self.registers[index] = self.registers[index].max(rank as u8);
+    }
+
+    pub fn estimate(&self) -> usize {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&rank| (-f64::from(rank)).exp2())
+            .sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        // linear counting is more accurate while few registers are set
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as usize
+    }
+}
+
+// avoids allocating the key for every count after the first
+fn bump(counts: &mut HashMap<String, u64>, key: &str) {
+    if let Some(count) = counts.get_mut(key) {
+        *count += 1;
+    } else {
+        counts.insert(key.to_string(), 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::board::StaticBoard;
+    use crate::input::SokobanInput;
+    use crate::state::{
+        settled_solution, update_solution_front, DistinctSketch, InitialPuzzleMetadata,
+        PrefixCacheMetadata, ReverseStatesMetadata, StatePathsMetadata, MAX_REVERSE_STATES,
+    };
+    use libafl::corpus::{Corpus, CorpusId, InMemoryCorpus, Testcase};
+    use libafl::state::{HasMetadata, HasSolutions, StdState};
+    use libafl_bolts::rands::StdRand;
+    use sokoban::Direction::{Down, Left, Right, Up};
+    use sokoban::State as SokobanState;
+    use std::time::Duration;
+
+    #[test]
+    fn test_settled_solution_is_on_front() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#######
+#_____#
+#_xm._#
+#_____#
+#######
+"#[..],
+        )
+        .unwrap();
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<SokobanInput>::new(),
+            InMemoryCorpus::new(),
+            &mut (),
+            &mut (),
+        )
+        .unwrap();
+        state.add_metadata(InitialPuzzleMetadata::new(puzzle));
+
+        // the first solution found walks around before pushing
+        for moves in [
+            vec![Up, Down, Right],
+            vec![Down, Up, Left, Right, Right],
+            vec![Right],
+        ] {
+            state
+                .solutions_mut()
+                .add(Testcase::new(SokobanInput::new(moves)))
+                .unwrap();
+            update_solution_front(&mut state, Duration::ZERO, false).unwrap();
+        }
+
+        let settled = settled_solution(&state).unwrap().unwrap();
+        assert_eq!(state.solutions().count(), 1);
+        assert!(state.solutions().ids().any(|id| id == settled));
+        assert_eq!(
+            state
+                .solutions()
+                .cloned_input_for_id(settled)
+                .unwrap()
+                .moves(),
+            &[Right]
+        );
+    }
+
+    #[test]
+    fn test_prefix_cache_hit() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#######
+#_____#
+#_xm._#
+#_____#
+#######
+"#[..],
+        )
+        .unwrap();
+        let board = StaticBoard::new(&puzzle);
+        let initial = board.compact(&puzzle);
+        let mut cache = PrefixCacheMetadata::default();
+
+        // walk back and forth, then push the crate onto the target
+        let mut moves = [Up, Down].repeat(20);
+        let (saved, replayed) = cache.replay(&board, &initial, &moves);
+        assert_eq!(saved, 0);
+        assert_eq!(replayed, Ok((initial.clone(), 0)));
+
+        moves.push(Right);
+        let (saved, replayed) = cache.replay(&board, &initial, &moves);
+        assert_eq!(saved, 32);
+        let (reached, pushes) = replayed.unwrap();
+        assert_eq!(pushes, 1);
+        assert!(reached.in_solution_state(&board));
+        assert_eq!(board.replay(&initial, &moves), Ok(reached));
+
+        // a different prefix doesn't pick up the cached states
+        moves[0] = Right;
+        let (saved, _) = cache.replay(&board, &initial, &moves);
+        assert_eq!(saved, 0);
+    }
+
+    #[test]
+    fn test_removed_holder_gives_up_its_state() {
+        let mut paths = StatePathsMetadata::default();
+        let (removed, longer, shorter) = (
+            CorpusId::from(0usize),
+            CorpusId::from(1usize),
+            CorpusId::from(2usize),
+        );
+        assert!(paths.offer(7, 3));
+        assert!(paths.claim(7, removed, &[Up, Down, Right]).is_none());
+        paths.remove(removed);
+
+        // a longer path takes the state over rather than deferring to the removed entry
+        let moves = [Up, Down, Left, Right, Right];
+        assert!(!paths.offer(7, moves.len()));
+        assert!(paths.claim(7, longer, &moves).is_none());
+
+        // so a shorter path replaces it, not the removed entry
+        let (previous, _) = paths.claim(7, shorter, &[Right]).unwrap();
+        assert_eq!(previous, longer);
+    }
+
+    #[test]
+    fn test_distinct_sketch() {
+        let mut sketch = DistinctSketch::default();
+        assert_eq!(sketch.estimate(), 0);
+        for value in 0..100 {
+            sketch.insert(value);
+            sketch.insert(value);
+        }
+        // duplicates don't count, and small counts are close to exact
+        let estimate = sketch.estimate();
+        assert!((95..105).contains(&estimate), "{estimate}");
+
+        for value in 0..100_000 {
+            sketch.insert(value);
+        }
+        let estimate = sketch.estimate();
+        assert!((95_000..105_000).contains(&estimate), "{estimate}");
+    }
 
-impl LastHallucinationMetadata {
-    pub fn hallucination_mut(&self) -> RefMut<Option<SokobanState>> {
-        self.hallucination.borrow_mut()
+    #[test]
+    fn test_reverse_states_age_out() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#####
+#x_.#
+#_m_#
+#####
+"#[..],
+        )
+        .unwrap();
+        let mut reverse = ReverseStatesMetadata::default();
+        reverse.insert(&puzzle, vec![Up]);
+        for _ in 0..MAX_REVERSE_STATES {
+            reverse.insert(&puzzle, Vec::new());
+        }
+        assert_eq!(reverse.inserted.len(), MAX_REVERSE_STATES);
+        let states = reverse.states.values().flatten().collect::<Vec<_>>();
+        assert_eq!(states.len(), MAX_REVERSE_STATES);
+        assert!(states.iter().all(|state| state.solution.is_empty()));
     }
 }
@@ -0,0 +1,274 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use libafl::corpus::Corpus;
+use libafl::state::{HasCorpus, HasMetadata};
+use libafl::Error;
+use libafl_bolts::impl_serdeany;
+use serde::{Deserialize, Serialize};
+use sokoban::{State as SokobanState, Tile};
+
+use crate::input::SokobanInput;
+use crate::state::{FilledTargetsMetadata, InitialPuzzleMetadata, StatePathsMetadata};
+use crate::util::{hash_sokoban_state, replay};
+
+// an intermediate goal on the way to solving the puzzle
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Subgoal {
+    // every one of these targets has a crate on it
+    Targets(Vec<(usize, usize)>),
+    // no crates left in the rectangle between these corners, inclusive
+    Room((usize, usize), (usize, usize)),
+}
+
+impl Subgoal {
+    pub fn reached(&self, puzzle: &SokobanState) -> bool {
+        match self {
+            Subgoal::Targets(targets) => {
+                targets.iter().all(|&target| puzzle[target] == Tile::Crate)
+            }
+            Subgoal::Room(from, to) => !puzzle.iter().any(|item| {
+                let (r, c) = item.position();
+                item.tile() == Tile::Crate && from.0 <= r && r <= to.0 && from.1 <= c && c <= to.1
+            }),
+        }
+    }
+
+    // make sure the sub-goal refers to squares that actually exist
+    pub fn check(&self, puzzle: &SokobanState) -> Result<(), String> {
+        let in_bounds = |&(r, c): &(usize, usize)| r < puzzle.rows() && c < puzzle.cols();
+        match self {
+            Subgoal::Targets(targets) => {
+                if let Some(missing) = targets
+                    .iter()
+                    .find(|target| !puzzle.targets().contains(target))
+                {
+                    return Err(format!("{missing:?} is not a target"));
+                }
+            }
+            Subgoal::Room(from, to) => {
+                if !in_bounds(from) || !in_bounds(to) {
+                    return Err(format!("room {self} lies outside the puzzle"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_position(position: &str) -> Result<(usize, usize), String> {
+    let (r, c) = position
+        .split_once(',')
+        .ok_or_else(|| format!("expected a position like 3,4 but got {position:?}"))?;
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<usize>()
+            .map_err(|e| format!("bad coordinate {n:?}: {e}"))
+    };
+    Ok((parse(r)?, parse(c)?))
+}
+
+// targets=r,c;r,c;... or room=r,c;r,c
+impl FromStr for Subgoal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, positions) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected targets=... or room=... but got {s:?}"))?;
+        let positions = positions
+            .split(';')
+            .map(parse_position)
+            .collect::<Result<Vec<_>, _>>()?;
+        match (kind, positions.as_slice()) {
+            ("targets", []) => Err("no targets given".to_string()),
+            ("targets", _) => Ok(Subgoal::Targets(positions)),
+            ("room", &[a, b]) => Ok(Subgoal::Room(
+                (a.0.min(b.0), a.1.min(b.1)),
+                (a.0.max(b.0), a.1.max(b.1)),
+            )),
+            ("room", _) => Err("a room needs exactly two corners".to_string()),
+            _ => Err(format!("unknown kind of sub-goal {kind:?}")),
+        }
+    }
+}
+
+impl Display for Subgoal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let join = |positions: &[(usize, usize)]| {
+            positions
+                .iter()
+                .map(|(r, c)| format!("{r},{c}"))
+                .collect::<Vec<_>>()
+                .join(";")
+        };
+        match self {
+            Subgoal::Targets(targets) => write!(f, "targets={}", join(targets)),
+            Subgoal::Room(from, to) => write!(f, "room={}", join(&[*from, *to])),
+        }
+    }
+}
+
+// the sub-goals still to be reached, in order
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct SubgoalsMetadata {
+    goals: Vec<Subgoal>,
+    current: usize,
+    reached: bool,
+}
+
+impl_serdeany!(SubgoalsMetadata);
+
+impl SubgoalsMetadata {
+    pub fn new(goals: Vec<Subgoal>) -> Self {
+        Self {
+            goals,
+            current: 0,
+            reached: false,
+        }
+    }
+
+    pub fn current(&self) -> Option<(usize, &Subgoal)> {
+        self.goals
+            .get(self.current)
+            .map(|goal| (self.current, goal))
+    }
+
+    pub fn mark_reached(&mut self) {
+        self.reached = true;
+    }
+
+    pub fn reached(&self) -> bool {
+        self.reached
+    }
+}
+
+// marks corpus entries which reached the sub-goal with this index
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SubgoalReachedMetadata {
+    goal: usize,
+}
+
+impl_serdeany!(SubgoalReachedMetadata);
+
+impl SubgoalReachedMetadata {
+    pub fn new(goal: usize) -> Self {
+        Self { goal }
+    }
+}
+
+// drops every corpus entry that didn't reach the current sub-goal and moves on to the next one;
+// what was seen before no longer counts, since the search starts over from the remaining entries
+pub fn advance_subgoal<S>(state: &mut S) -> Result<(), Error>
+where
+    S: HasCorpus<Input = SokobanInput> + HasMetadata,
+{
+    let goal = state.metadata::<SubgoalsMetadata>()?.current;
+    let missed = state
+        .corpus()
+        .ids()
+        .filter(|&id| {
+            state.corpus().get(id).is_ok_and(|testcase| {
+                testcase
+                    .borrow()
+                    .metadata::<SubgoalReachedMetadata>()
+                    .map_or(true, |reached| reached.goal != goal)
+            })
+        })
+        .collect::<Vec<_>>();
+    for id in missed {
+        state.corpus_mut().remove(id)?;
+    }
+    // the scheduler may have been in the middle of one of the removed entries
+    *state.corpus_mut().current_mut() = None;
+
+    let initial = state.metadata::<InitialPuzzleMetadata>()?.initial();
+    let mut paths = StatePathsMetadata::default();
+    for id in state.corpus().ids() {
+        let moves = state.corpus().cloned_input_for_id(id)?.moves().to_vec();
+        let reached = replay(&initial, &moves)
+            .map_err(|(index, _)| Error::illegal_state(format!("corpus entry fails at {index}")))?;
+        let hash = hash_sokoban_state(&reached, true);
+        paths.offer(hash, moves.len());
+        paths.claim(hash, id, &moves);
+    }
+    state.add_metadata(paths);
+    state.add_metadata(FilledTargetsMetadata::default());
+
+    let subgoals = state.metadata_mut::<SubgoalsMetadata>()?;
+    subgoals.current += 1;
+    subgoals.reached = false;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::input::SokobanInput;
+    use crate::state::InitialPuzzleMetadata;
+    use crate::subgoal::{advance_subgoal, Subgoal, SubgoalReachedMetadata, SubgoalsMetadata};
+    use libafl::corpus::{Corpus, InMemoryCorpus, Testcase};
+    use libafl::state::{HasCorpus, HasMetadata, StdState};
+    use libafl_bolts::rands::StdRand;
+    use sokoban::Direction::{Left, Right, Up};
+    use sokoban::State as SokobanState;
+
+    #[test]
+    fn test_subgoals_in_order() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#######
+#.m_m.#
+#__x__#
+#######
+"#[..],
+        )
+        .unwrap();
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<SokobanInput>::new(),
+            InMemoryCorpus::new(),
+            &mut (),
+            &mut (),
+        )
+        .unwrap();
+        state.add_metadata(InitialPuzzleMetadata::new(puzzle.clone()));
+        let goals = ["targets=1,5", "targets=1,1"]
+            .map(|goal| goal.parse::<Subgoal>().unwrap())
+            .to_vec();
+        state.add_metadata(SubgoalsMetadata::new(goals));
+
+        let (goal, subgoal) = state
+            .metadata::<SubgoalsMetadata>()
+            .unwrap()
+            .current()
+            .unwrap();
+        assert_eq!((goal, subgoal.to_string()), (0, "targets=1,5".to_string()));
+
+        // only the entry which reached the first sub-goal carries on to the second
+        let mut reached = Testcase::new(SokobanInput::new(vec![Up, Right]));
+        reached.add_metadata(SubgoalReachedMetadata::new(0));
+        let reached = state.corpus_mut().add(reached).unwrap();
+        state
+            .corpus_mut()
+            .add(Testcase::new(SokobanInput::new(vec![Left])))
+            .unwrap();
+        state
+            .metadata_mut::<SubgoalsMetadata>()
+            .unwrap()
+            .mark_reached();
+        advance_subgoal(&mut state).unwrap();
+
+        assert_eq!(state.corpus().ids().collect::<Vec<_>>(), [reached]);
+        let subgoals = state.metadata::<SubgoalsMetadata>().unwrap();
+        assert!(!subgoals.reached());
+        let (goal, subgoal) = subgoals.current().unwrap();
+        assert_eq!((goal, subgoal.to_string()), (1, "targets=1,1".to_string()));
+
+        advance_subgoal(&mut state).unwrap();
+        assert!(state
+            .metadata::<SubgoalsMetadata>()
+            .unwrap()
+            .current()
+            .is_none());
+    }
+}
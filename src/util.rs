@@ -1,3 +1,4 @@
+use crate::board::StaticBoard;
 use sokoban::Direction::{Down, Left, Right, Up};
 use sokoban::{Direction, State as SokobanState, Tile};
 use std::collections::hash_map::{DefaultHasher, Entry};
@@ -23,6 +24,87 @@ pub fn find_crates(puzzle: &SokobanState) -> Vec<(usize, usize)> {
         .collect()
 }
 
+// a crate pushed onto this square can never be moved off of it again
+pub fn is_dead_square(puzzle: &SokobanState, position: (usize, usize)) -> bool {
+    if puzzle.targets().contains(&position) {
+        return false;
+    }
+    let is_wall = |direction: Direction| {
+        direction
+            .go(position)
+            .is_some_and(|next| puzzle[next] == Tile::Wall)
+    };
+    (is_wall(Up) || is_wall(Down)) && (is_wall(Left) || is_wall(Right))
+}
+
+// a crate that can be pushed along neither axis, by the usual freeze rules: an axis is blocked by
+// a wall on either side, dead squares on both sides, or a neighbouring crate that is itself
+// frozen once this one counts as a wall
+pub fn is_frozen(puzzle: &SokobanState, position: (usize, usize)) -> bool {
+    frozen(puzzle, position, &mut Vec::new())
+}
+
+fn frozen(
+    puzzle: &SokobanState,
+    position: (usize, usize),
+    walled: &mut Vec<(usize, usize)>,
+) -> bool {
+    [[Left, Right], [Up, Down]].into_iter().all(|axis| {
+        let sides = axis.map(|direction| direction.go(position));
+        let tile = |side: Option<(usize, usize)>| match side {
+            Some(side) if !walled.contains(&side) => puzzle[side],
+            _ => Tile::Wall,
+        };
+        if sides.iter().any(|&side| tile(side) == Tile::Wall)
+            || sides
+                .iter()
+                .all(|side| side.is_some_and(|side| is_dead_square(puzzle, side)))
+        {
+            return true;
+        }
+        walled.push(position);
+        let blocked = sides.into_iter().flatten().any(|side| {
+            puzzle[side] == Tile::Crate && !walled.contains(&side) && frozen(puzzle, side, walled)
+        });
+        walled.pop();
+        blocked
+    })
+}
+
+pub fn count_filled(puzzle: &SokobanState) -> usize {
+    puzzle
+        .targets()
+        .iter()
+        .filter(|&&target| puzzle[target] == Tile::Crate)
+        .count()
+}
+
+// how far the crates are from being solved: each crate's distance to its nearest target
+pub fn crate_distance(puzzle: &SokobanState) -> usize {
+    find_crates(puzzle)
+        .into_iter()
+        .map(|(r, c)| {
+            puzzle
+                .targets()
+                .iter()
+                .map(|&(tr, tc)| r.abs_diff(tr) + c.abs_diff(tc))
+                .min()
+                .unwrap_or(0)
+        })
+        .sum()
+}
+
+// which targets have a crate on them, as a bitset in the order of the puzzle's targets
+pub fn filled_targets(puzzle: &SokobanState) -> Vec<u64> {
+    let mut filled = vec![0; puzzle.targets().len().div_ceil(64)];
+    for (i, &target) in puzzle.targets().iter().enumerate() {
+        if puzzle[target] == Tile::Crate {
+            filled[i / 64] |= 1 << (i % 64);
+        }
+    }
+    filled
+}
+
 fn explore_local(
     start: (usize, usize),
     destination: (usize, usize),
@@ -125,137 +207,256 @@ pub fn can_go_to(
     false
 }
 
-// same as explore_local, but makes sure that the player can get to the specified position
-fn push_local(
-    player: (usize, usize),
-    start: (usize, usize),
-    destination: (usize, usize),
-    hallucinated: &mut SokobanState,
-    prev_moves: &mut HashMap<(usize, usize), Option<Direction>>,
-    new_moves: &mut Vec<(usize, usize)>,
-) -> bool {
-    for direction in POSSIBLE_MOVES {
-        if let Some(next) = direction.go(start) {
-            if let Some(push_point) = opposite(direction).go(start) {
-                if next.0 < hallucinated.rows()
-                    && next.1 < hallucinated.cols()
-                    && hallucinated[next] == Tile::Floor
-                    && push_point.0 < hallucinated.rows()
-                    && push_point.1 < hallucinated.cols()
-                    && hallucinated[push_point] == Tile::Floor
-                {
-                    match prev_moves.entry(next) {
-                        Entry::Occupied(_) => continue, // avoid backtracking
-                        Entry::Vacant(e) => {
-                            // we need to hallucinate that the crate *is* there
-                            hallucinated[start] = Tile::Crate;
-
-                            // check that the player can get there
-                            if can_go_to(player, push_point, hallucinated) {
-                                e.insert(Some(direction));
-                                if next == destination {
-                                    hallucinated[start] = Tile::Floor;
-                                    return true;
-                                }
-                                new_moves.push(next);
-                            }
-                            hallucinated[start] = Tile::Floor;
-                        }
-                    }
-                }
+// whether moving the player that way from this state pushes a crate
+pub fn is_push(before: &SokobanState, direction: Direction) -> bool {
+    direction
+        .go(before.player())
+        .is_some_and(|next| before[next] == Tile::Crate)
+}
+
+pub fn count_pushes(initial: &SokobanState, moves: &[Direction]) -> Option<usize> {
+    let board = StaticBoard::new(initial);
+    board.count_pushes(&board.compact(initial), moves)
+}
+
+// the moves in the usual LURD notation: a letter per move, capitalised where it pushes a crate
+pub fn lurd(initial: &SokobanState, moves: &[Direction]) -> Option<String> {
+    let mut lurd = String::with_capacity(moves.len());
+    let mut current = initial.clone();
+    for &direction in moves {
+        let pushed = is_push(&current, direction);
+        current = current.move_player(direction).ok()?;
+        let letter = match direction {
+            Direction::Left => 'l',
+            Direction::Up => 'u',
+            Direction::Right => 'r',
+            Direction::Down => 'd',
+        };
+        lurd.push(if pushed {
+            letter.to_ascii_uppercase()
+        } else {
+            letter
+        });
+    }
+    Some(lurd)
+}
+
+// the moves of a LURD, whichever case it gives the pushes in
+pub fn parse_lurd(lurd: &str) -> Option<Vec<Direction>> {
+    lurd.chars()
+        .map(|letter| match letter.to_ascii_lowercase() {
+            'l' => Some(Direction::Left),
+            'u' => Some(Direction::Up),
+            'r' => Some(Direction::Right),
+            'd' => Some(Direction::Down),
+            _ => None,
+        })
+        .collect()
+}
+
+// replays the moves, or reports the first illegal one along with the board it was attempted on
+pub fn replay(
+    initial: &SokobanState,
+    moves: &[Direction],
+) -> Result<SokobanState, (usize, SokobanState)> {
+    let board = StaticBoard::new(initial);
+    board
+        .replay(&board.compact(initial), moves)
+        .map(|reached| board.expand(&reached))
+        .map_err(|(index, last_state)| (index, board.expand(&last_state)))
+}
+
+// the state before the first push and after every push, along with the state the moves end in
+pub fn trajectory(
+    initial: &SokobanState,
+    moves: &[Direction],
+) -> Option<(Vec<SokobanState>, SokobanState)> {
+    let board = StaticBoard::new(initial);
+    let (steps, reached) = board.trajectory(&board.compact(initial), moves)?;
+    let steps = steps.iter().map(|step| board.expand(step)).collect();
+    Some((steps, board.expand(&reached)))
+}
+
+// the same puzzle, but with the player moved to the given position
+pub fn with_player(puzzle: &SokobanState, player: (usize, usize)) -> Option<SokobanState> {
+    SokobanState::new(
+        puzzle.iter().map(|item| item.tile()).collect(),
+        player,
+        puzzle.targets().to_vec(),
+        puzzle.rows(),
+        puzzle.cols(),
+    )
+    .ok()
+}
+
+// the solved configuration of the puzzle, with the player in each region next to a crate
+pub fn goal_states(initial: &SokobanState) -> Vec<SokobanState> {
+    if find_crates(initial).len() != initial.targets().len() {
+        return Vec::new();
+    }
+
+    let mut solved = initial.clone();
+    for moved in find_crates(initial) {
+        solved[moved] = Tile::Floor;
+    }
+    for &target in initial.targets() {
+        solved[target] = Tile::Crate;
+    }
+
+    let next_to_crate = |position: (usize, usize)| {
+        POSSIBLE_MOVES.iter().any(|direction| {
+            direction.go(position).is_some_and(|next| {
+                next.0 < solved.rows() && next.1 < solved.cols() && solved[next] == Tile::Crate
+            })
+        })
+    };
+
+    let mut visited = HashMap::new();
+    let mut goals = Vec::new();
+    for item in solved.iter() {
+        let start = item.position();
+        if item.tile() != Tile::Floor || visited.contains_key(&start) {
+            continue;
+        }
+
+        // flood-fill the region, remembering the first position from which a crate can be pulled
+        visited.insert(start, None);
+        let mut new_moves = vec![start];
+        let mut puller = None;
+        while let Some(position) = new_moves.pop() {
+            if puller.is_none() && next_to_crate(position) {
+                puller = Some(position);
             }
+            explore_local(
+                position,
+                (usize::MAX, usize::MAX),
+                &solved,
+                &mut visited,
+                &mut new_moves,
+            );
+        }
+
+        if let Some(puller) = puller {
+            goals.extend(with_player(&solved, puller));
         }
     }
-    false
+    goals
 }
 
-// this is the same concept as go_to, but ensures the player can push at any point
-pub fn push_to(
-    start: (usize, usize),
-    destination: (usize, usize),
-    puzzle: &SokobanState,
-) -> Option<Vec<Direction>> {
-    if start.0 < puzzle.rows()
-        && start.1 < puzzle.cols()
-        && puzzle[start] == Tile::Crate
-        && destination.0 < puzzle.rows()
-        && destination.1 < puzzle.cols()
-        && puzzle[destination] == Tile::Floor
-    {
-        if start == destination {
-            return Some(Vec::new());
-        }
+// the reverse of a move: the player steps in the direction given and, when pulling, drags the
+// crate behind them onto the position they left
+pub fn pull_player(puzzle: SokobanState, direction: Direction, pull: bool) -> Option<SokobanState> {
+    let player = puzzle.player();
+    let next = direction.go(player)?;
+    if next.0 >= puzzle.rows() || next.1 >= puzzle.cols() || puzzle[next] != Tile::Floor {
+        return None;
+    }
 
-        // we need to hallucinate that the crate isn't there
-        let mut hallucinated = puzzle.clone();
-        hallucinated[start] = Tile::Floor;
+    let pulled = if pull {
+        let behind = opposite(direction).go(player)?;
+        if behind.0 >= puzzle.rows() || behind.1 >= puzzle.cols() || puzzle[behind] != Tile::Crate {
+            return None;
+        }
+        Some(behind)
+    } else {
+        None
+    };
+
+    let mut puzzle = puzzle.move_player(direction).ok()?;
+    if let Some(behind) = pulled {
+        puzzle[behind] = Tile::Floor;
+        puzzle[player] = Tile::Crate;
+    }
+    Some(puzzle)
+}
 
-        let mut prev_moves = HashMap::new();
-        prev_moves.insert(start, None);
-        let mut new_moves = vec![start];
+// the position the player pushes from and the direction they push in
+type PushStep = ((usize, usize), Direction);
+
+// every push made by the moves, along with the state they end in
+fn push_steps(
+    initial: &SokobanState,
+    moves: &[Direction],
+) -> Option<(Vec<PushStep>, SokobanState)> {
+    let mut pushes = Vec::new();
+    let mut current = initial.clone();
+    for &direction in moves {
+        if is_push(&current, direction) {
+            pushes.push((current.player(), direction));
+        }
+        current = current.move_player(direction).ok()?;
+    }
+    Some((pushes, current))
+}
 
-        while !new_moves.is_empty() {
-            let mut last_moves = Vec::new();
-            core::mem::swap(&mut new_moves, &mut last_moves);
-            for prev in last_moves {
-                let player = prev_moves
-                    .get(&prev)
-                    .unwrap()
-                    .map(|direction| opposite(direction).go(prev).unwrap())
-                    .unwrap_or(puzzle.player());
-                if push_local(
-                    player,
-                    prev,
-                    destination,
-                    &mut hallucinated,
-                    &mut prev_moves,
-                    &mut new_moves,
-                ) {
-                    let mut crate_moves = VecDeque::new();
-                    let mut next = destination;
-                    // walk backwards through the flood-fill
-                    while let Some(&Some(direction)) = prev_moves.get(&next) {
-                        next = opposite(direction).go(next).unwrap();
-                        crate_moves.push_front(direction);
-                    }
+// makes the pushes in order, taking the shortest walk to each of them and finally to the end
+fn walk_between(
+    initial: &SokobanState,
+    pushes: &[PushStep],
+    end: (usize, usize),
+) -> Option<(Vec<Direction>, SokobanState)> {
+    let mut moves = Vec::new();
+    let mut current = initial.clone();
+    for &(from, direction) in pushes {
+        let walk = go_to(current.player(), from, &current)?;
+        current = walk
+            .iter()
+            .copied()
+            .try_fold(current, |current, direction| current.move_player(direction))
+            .ok()?;
+        if !is_push(&current, direction) {
+            return None;
+        }
+        current = current.move_player(direction).ok()?;
+        moves.extend(walk);
+        moves.push(direction);
+    }
+    let walk = go_to(current.player(), end, &current)?;
+    current = walk
+        .iter()
+        .copied()
+        .try_fold(current, |current, direction| current.move_player(direction))
+        .ok()?;
+    moves.extend(walk);
+    Some((moves, current))
+}
 
-                    hallucinated[start] = Tile::Crate;
-
-                    let mut assembled = Vec::new();
-                    let mut last_executed = 0;
-                    let mut last_position = start;
-                    for &next_move in crate_moves.iter() {
-                        // execute the player moves that we haven't done yet
-                        hallucinated = assembled[last_executed..]
-                            .iter()
-                            .try_fold(hallucinated, |puzzle, &direction| {
-                                puzzle.move_player(direction)
-                            })
-                            .unwrap();
-                        last_executed = assembled.len();
-
-                        // queue the moves to get the player to the push point
-                        if let Some(path) = go_to(
-                            hallucinated.player(),
-                            opposite(next_move).go(last_position).unwrap(),
-                            &hallucinated,
-                        ) {
-                            assembled.extend(path);
-                        } else {
-                            eprintln!("while attempting to apply {crate_moves:?} to {puzzle:?}");
-                            panic!("unable to queue movement {next_move:?} for box at {last_position:?}: {hallucinated:?} (player at {:?})", hallucinated.player());
-                        }
-                        // queue the moves to push the box
-                        assembled.push(next_move);
-                        last_position = next_move.go(last_position).unwrap();
-                    }
+fn same_position(a: &SokobanState, b: &SokobanState) -> bool {
+    a.player() == b.player() && a.iter().zip(b.iter()).all(|(a, b)| a.tile() == b.tile())
+}
 
-                    return Some(assembled);
+// re-plans the walking between pushes on the actual board, then swaps neighbouring pushes
+// wherever that saves walking; the result is never longer and ends in the same state
+pub fn reroute_walks(initial: &SokobanState, moves: &[Direction]) -> Vec<Direction> {
+    let Some((mut pushes, end)) = push_steps(initial, moves) else {
+        return moves.to_vec();
+    };
+    let mut best = match walk_between(initial, &pushes, end.player()) {
+        Some((rerouted, reached))
+            if rerouted.len() <= moves.len() && same_position(&reached, &end) =>
+        {
+            rerouted
+        }
+        _ => moves.to_vec(),
+    };
+
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 1..pushes.len() {
+            pushes.swap(i - 1, i);
+            match walk_between(initial, &pushes, end.player()) {
+                Some((rerouted, reached))
+                    if rerouted.len() < best.len() && same_position(&reached, &end) =>
+                {
+                    best = rerouted;
+                    improved = true;
                 }
+                _ => pushes.swap(i - 1, i),
             }
         }
     }
-    None
+    best
 }
 
 pub fn hash_sokoban_state(state: &SokobanState, include_player: bool) -> u64 {
@@ -271,8 +472,10 @@ pub fn hash_sokoban_state(state: &SokobanState, include_player: bool) -> u64 {
 
 #[cfg(test)]
 mod test {
-    use crate::util::{go_to, push_to};
-    use sokoban::Direction::{Right, Up};
+    use crate::util::{
+        count_pushes, go_to, goal_states, is_frozen, lurd, parse_lurd, pull_player, reroute_walks,
+    };
+    use sokoban::Direction::{Down, Left, Right, Up};
     use sokoban::{State as SokobanState, Tile};
 
     #[test]
@@ -412,87 +615,110 @@ mod test {
     }
 
     #[test]
-    fn test_push_to_simple() {
+    fn test_lurd() {
         let puzzle = SokobanState::parse(
             &br#"
-####################
-#__________________#
-#__________________#
-#______________m___#
-#_____________x____#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-#__________________#
-####################
+#######
+#_____#
+#_xm._#
+#_____#
+#######
 "#[..],
         )
         .unwrap();
 
-        let moves = push_to(
-            Up.go(Right.go(puzzle.player()).unwrap()).unwrap(),
-            (15, 3),
-            &puzzle,
+        let moves = [Right, Right, Up, Right, Down, Left, Down];
+        assert_eq!(lurd(&puzzle, &moves).as_deref(), Some("RRurDld"));
+        assert!(lurd(&puzzle, &[Up, Up]).is_none());
+        assert_eq!(parse_lurd("RRurDld").as_deref(), Some(&moves[..]));
+        assert!(parse_lurd("RRx").is_none());
+    }
+
+    #[test]
+    fn test_pull_undoes_push() {
+        let puzzle = SokobanState::parse(
+            &br#"
+#########
+#_______#
+#_xm_.__#
+#_______#
+#########
+"#[..],
         )
-        .expect("Couldn't find path to (15, 3)!");
-        println!("{:?}", moves);
-        let puzzle = moves
-            .into_iter()
-            .try_fold(puzzle, |puzzle, direction| puzzle.move_player(direction))
-            .expect("Should not make invalid moves!");
+        .unwrap();
+
+        let goals = goal_states(&puzzle);
+        assert_eq!(goals.len(), 1);
+        assert!(goals[0].in_solution_state());
+
+        let pushed = puzzle.clone().move_player(Right).unwrap();
+        let pulled = pull_player(pushed, Left, true).expect("Should be able to pull the crate!");
+        assert_eq!(pulled[(2, 3)], Tile::Crate);
+        assert_eq!(pulled[(2, 4)], Tile::Floor);
+        assert_eq!(pulled.player(), puzzle.player());
 
-        assert_eq!(puzzle[(15, 3)], Tile::Crate);
+        assert!(pull_player(pulled, Right, false).is_none());
     }
 
     #[test]
-    fn test_path_to_around_wall() {
+    fn test_reroute_walks() {
         let puzzle = SokobanState::parse(
             &br#"
-####################
-#________#_________#
-#________#_____m___#
-#________#____x____#
-#________#_________#
-#________#_________#
-#________#_________#
-#________#_________#
-#________#_________#
-#________#_________#
-#________#_________#
-#________#_________#
-#________#_________#
-#________#_________#
-#________#_________#
-#________#_________#
-#__________________#
-#__________________#
-####################
+#######
+#_____#
+#_m_m_#
+#__x__#
+#.___.#
+#######
 "#[..],
         )
         .unwrap();
 
-        let moves = push_to(
-            Up.go(Right.go(puzzle.player()).unwrap()).unwrap(),
-            (3, 3),
-            &puzzle,
-        )
-        .expect("Couldn't find path to (3, 3)!");
-        println!("{:?}", moves);
-        let puzzle = moves
-            .into_iter()
+        // dither on the way to the first push
+        let moves = [Up, Up, Left, Right, Left, Down, Up, Right, Right, Down];
+        assert_eq!(count_pushes(&puzzle, &moves), Some(2));
+
+        let rerouted = reroute_walks(&puzzle, &moves);
+        assert_eq!(rerouted.len(), 8);
+        let expected = moves
+            .iter()
+            .copied()
+            .try_fold(puzzle.clone(), |puzzle, direction| {
+                puzzle.move_player(direction)
+            })
+            .unwrap();
+        let reached = rerouted
+            .iter()
+            .copied()
             .try_fold(puzzle, |puzzle, direction| puzzle.move_player(direction))
-            .expect("Should not make invalid moves!");
+            .unwrap();
+        assert_eq!(reached.player(), expected.player());
+        assert!(reached
+            .iter()
+            .zip(expected.iter())
+            .all(|(a, b)| a.tile() == b.tile()));
+    }
 
-        assert_eq!(puzzle[(3, 3)], Tile::Crate);
+    #[test]
+    fn test_is_frozen() {
+        let puzzle = SokobanState::parse(
+            &br#"
+##########
+#________#
+#_mm___m_#
+#_mm_____#
+#x____m.m#
+##########
+"#[..],
+        )
+        .unwrap();
+        // a block of four holds itself in place
+        assert!(is_frozen(&puzzle, (2, 2)));
+        assert!(is_frozen(&puzzle, (3, 3)));
+        // a crate in the open, or against a single wall, still moves along it
+        assert!(!is_frozen(&puzzle, (2, 7)));
+        assert!(!is_frozen(&puzzle, (4, 6)));
+        // and so is one in a corner
+        assert!(is_frozen(&puzzle, (4, 8)));
     }
 }